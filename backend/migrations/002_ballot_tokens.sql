@@ -0,0 +1,22 @@
+-- Upgrades a version 1 database to version 2.
+-- Adds ballot tokens and the vote_changes table.
+
+BEGIN TRANSACTION;
+
+ALTER TABLE votes ADD COLUMN token TEXT UNIQUE;
+-- Ballots cast before this migration get a token that nobody knows, so they can't be changed
+UPDATE votes SET token = md5(random()::TEXT || clock_timestamp()::TEXT);
+ALTER TABLE votes ALTER COLUMN token SET NOT NULL;
+
+CREATE TABLE vote_changes (
+    poll_id TEXT NOT NULL REFERENCES polls(id),
+    token TEXT NOT NULL,
+    old_preferences TEXT[] NOT NULL,
+    -- NULL if the ballot was retracted
+    new_preferences TEXT[],
+    changed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+UPDATE db_info SET version = 2;
+
+COMMIT;
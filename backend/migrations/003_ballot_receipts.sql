@@ -0,0 +1,11 @@
+-- Upgrades a version 2 database to version 3.
+-- Adds ballot receipts.
+
+BEGIN TRANSACTION;
+
+ALTER TABLE votes ADD COLUMN receipt TEXT;
+ALTER TABLE votes ADD COLUMN receipt_nonce TEXT;
+
+UPDATE db_info SET version = 3;
+
+COMMIT;
@@ -0,0 +1,13 @@
+-- Upgrades a version 3 database to version 4.
+-- Adds admin tokens to polls.
+
+BEGIN TRANSACTION;
+
+ALTER TABLE polls ADD COLUMN admin_token TEXT;
+-- Polls created before this migration get a token that nobody knows
+UPDATE polls SET admin_token = md5(random()::TEXT || clock_timestamp()::TEXT);
+ALTER TABLE polls ALTER COLUMN admin_token SET NOT NULL;
+
+UPDATE db_info SET version = 4;
+
+COMMIT;
@@ -0,0 +1,10 @@
+-- Upgrades a version 4 database to version 5.
+-- Allows ballots without a voter IP address, such as ones imported from BLT files.
+
+BEGIN TRANSACTION;
+
+ALTER TABLE votes ALTER COLUMN voter_ip DROP NOT NULL;
+
+UPDATE db_info SET version = 5;
+
+COMMIT;
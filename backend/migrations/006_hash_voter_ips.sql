@@ -0,0 +1,52 @@
+-- Upgrades a version 5 database to version 6.
+-- Replaces voter IP addresses with keyed hashes, and deletes them for polls that have ended.
+--
+-- The hashes must match the ones the server computes for new votes, so set
+-- `betterpoll.server_secret`, `betterpoll.ipv4_prefix_length` and `betterpoll.ipv6_prefix_length`
+-- to the `server_secret`, `ipv4_prefix_length` and `ipv6_prefix_length` from Rocket.toml (the
+-- prefix lengths default to 32 and 64) when running this, for example:
+--     PGOPTIONS="-c betterpoll.server_secret=<secret> -c betterpoll.ipv4_prefix_length=32 -c betterpoll.ipv6_prefix_length=64" psql -f 006_hash_voter_ips.sql
+
+BEGIN TRANSACTION;
+
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+ALTER TABLE votes ADD COLUMN voter_ip_hash TEXT;
+
+-- Equivalent to network_prefix in the backend: IPv4-mapped IPv6 addresses are treated as IPv4,
+-- and the address is reduced to the network it's in
+CREATE FUNCTION pg_temp.voter_network(ip INET) RETURNS TEXT AS $$
+    SELECT host(network(set_masklen(
+        unmapped.ip,
+        CASE family(unmapped.ip)
+            WHEN 4 THEN LEAST(current_setting('betterpoll.ipv4_prefix_length')::INTEGER, 32)
+            ELSE LEAST(current_setting('betterpoll.ipv6_prefix_length')::INTEGER, 128)
+        END
+    )))
+    FROM (
+        SELECT CASE
+            WHEN family(ip) = 6 AND ip << '::ffff:0.0.0.0/96' THEN substring(host(ip) FROM 8)::INET
+            ELSE ip
+        END AS ip
+    ) AS unmapped
+$$ LANGUAGE SQL;
+
+-- Equivalent to RankedChoiceVote::hash_ip in the backend: each part is prefixed with its length
+UPDATE votes SET voter_ip_hash = encode(
+    hmac(
+        int8send(8) || convert_to('voter_ip', 'UTF8') ||
+            int8send(octet_length(pg_temp.voter_network(voter_ip))) ||
+            convert_to(pg_temp.voter_network(voter_ip), 'UTF8'),
+        convert_to(current_setting('betterpoll.server_secret'), 'UTF8'),
+        'sha256'
+    ),
+    'hex'
+)
+WHERE voter_ip IS NOT NULL
+AND poll_id IN (SELECT id FROM polls WHERE expires_at > NOW());
+
+ALTER TABLE votes DROP COLUMN voter_ip;
+
+UPDATE db_info SET version = 6;
+
+COMMIT;
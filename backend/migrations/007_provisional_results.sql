@@ -0,0 +1,10 @@
+-- Upgrades a version 6 database to version 7.
+-- Lets poll creators show the standings before their poll ends.
+
+BEGIN TRANSACTION;
+
+ALTER TABLE polls ADD COLUMN provisional_results INTEGER NOT NULL DEFAULT 0;
+
+UPDATE db_info SET version = 7;
+
+COMMIT;
@@ -0,0 +1,43 @@
+-- Upgrades a version 7 database to version 8.
+-- Adds webhooks, which are notified when something happens to a poll.
+
+BEGIN TRANSACTION;
+
+-- URLs that are sent a signed POST request when something happens to a poll
+CREATE TABLE webhooks (
+    id BIGSERIAL PRIMARY KEY,
+    poll_id TEXT NOT NULL REFERENCES polls(id),
+    url TEXT NOT NULL,
+    -- Key for the HMAC-SHA256 signature of each request
+    secret TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    -- Whether the poll_closed event has been queued
+    closed_notified BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+-- Every request that has been or will be sent to a webhook.
+-- The URL and secret are copied so that poll_deleted events can be sent after the webhook is deleted.
+CREATE TABLE webhook_deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    webhook_id BIGINT NOT NULL,
+    poll_id TEXT NOT NULL,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    -- NULL once the request has succeeded or there are no attempts left
+    next_attempt_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    delivered_at TIMESTAMP WITH TIME ZONE,
+    -- HTTP status code of the latest attempt, or NULL if no response was received
+    last_status INTEGER,
+    last_error TEXT
+);
+
+CREATE INDEX webhook_deliveries_due ON webhook_deliveries (next_attempt_at)
+    WHERE next_attempt_at IS NOT NULL;
+
+UPDATE db_info SET version = 8;
+
+COMMIT;
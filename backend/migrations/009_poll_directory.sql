@@ -0,0 +1,13 @@
+-- Upgrades a version 8 database to version 9.
+-- Lets polls be listed in the public directory.
+
+BEGIN TRANSACTION;
+
+ALTER TABLE polls ADD COLUMN listed BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX polls_search ON polls
+    USING GIN (to_tsvector('english', title || ' ' || description)) WHERE listed;
+
+UPDATE db_info SET version = 9;
+
+COMMIT;
@@ -0,0 +1,40 @@
+-- Upgrades a version 9 database to version 10.
+-- Adds invitation-only polls and queued emails.
+
+BEGIN TRANSACTION;
+
+ALTER TABLE polls
+    ADD COLUMN invite_only BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN creator_email TEXT,
+    ADD COLUMN results_emailed BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- One-time voting links for invitation-only polls.
+-- Only a keyed hash of each token is stored, and not who it was sent to, so ballots can't be traced to voters.
+CREATE TABLE invitations (
+    poll_id TEXT NOT NULL REFERENCES polls(id),
+    token_hash TEXT NOT NULL,
+    used BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY (poll_id, token_hash)
+);
+
+-- Every email that has been or will be sent.
+-- Bodies are emptied once they've been sent, since invitations contain voting links.
+CREATE TABLE emails (
+    id BIGSERIAL PRIMARY KEY,
+    poll_id TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    body TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    -- NULL once the email has been sent or there are no attempts left
+    next_attempt_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    sent_at TIMESTAMP WITH TIME ZONE,
+    last_error TEXT
+);
+
+CREATE INDEX emails_due ON emails (next_attempt_at) WHERE next_attempt_at IS NOT NULL;
+
+UPDATE db_info SET version = 10;
+
+COMMIT;
@@ -13,22 +13,118 @@ CREATE TABLE polls (
     expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
     num_winners INTEGER NOT NULL,
     -- 0 for Schulze
-    method INTEGER NOT NULL
+    method INTEGER NOT NULL,
+    -- Secret given to the poll's creator
+    admin_token TEXT NOT NULL,
+    -- Who can see the standings before the poll ends: 0 for nobody, 1 for the creator, 2 for everyone
+    provisional_results INTEGER NOT NULL DEFAULT 0,
+    -- Whether the poll is shown in the public directory
+    listed BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Whether only people with an emailed invitation can vote
+    invite_only BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Where to email the results when the poll ends, if anywhere
+    creator_email TEXT,
+    -- Whether the results email has been queued
+    results_emailed BOOLEAN NOT NULL DEFAULT FALSE
 );
 
+-- Full-text search of the public directory
+CREATE INDEX polls_search ON polls
+    USING GIN (to_tsvector('english', title || ' ' || description)) WHERE listed;
+
 CREATE TABLE votes (
     poll_id TEXT NOT NULL REFERENCES polls(id),
-    voter_ip INET NOT NULL,
+    -- Given to the voter so that they can change or retract their ballot
+    token TEXT NOT NULL UNIQUE,
+    -- Keyed hash of the voter's IP address, used only to detect double voting.
+    -- NULL for ballots that weren't cast through the API, and cleared once the poll ends.
+    voter_ip_hash TEXT,
     -- Reserved for future use
     voter_fingerprint TEXT,
     -- 1st choice is in preferences[0], etc.
-    preferences TEXT[] NOT NULL
+    preferences TEXT[] NOT NULL,
+    -- Published once the poll ends so voters can check their ballot was counted;
+    -- NULL for ballots that weren't cast through the API
+    receipt TEXT,
+    receipt_nonce TEXT
+);
+
+-- Every change made to a ballot after it was cast
+CREATE TABLE vote_changes (
+    poll_id TEXT NOT NULL REFERENCES polls(id),
+    token TEXT NOT NULL,
+    old_preferences TEXT[] NOT NULL,
+    -- NULL if the ballot was retracted
+    new_preferences TEXT[],
+    changed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- URLs that are sent a signed POST request when something happens to a poll
+CREATE TABLE webhooks (
+    id BIGSERIAL PRIMARY KEY,
+    poll_id TEXT NOT NULL REFERENCES polls(id),
+    url TEXT NOT NULL,
+    -- Key for the HMAC-SHA256 signature of each request
+    secret TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    -- Whether the poll_closed event has been queued
+    closed_notified BOOLEAN NOT NULL DEFAULT FALSE
 );
 
+-- Every request that has been or will be sent to a webhook.
+-- The URL and secret are copied so that poll_deleted events can be sent after the webhook is deleted.
+CREATE TABLE webhook_deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    webhook_id BIGINT NOT NULL,
+    poll_id TEXT NOT NULL,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    -- NULL once the request has succeeded or there are no attempts left
+    next_attempt_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    delivered_at TIMESTAMP WITH TIME ZONE,
+    -- HTTP status code of the latest attempt, or NULL if no response was received
+    last_status INTEGER,
+    last_error TEXT
+);
+
+CREATE INDEX webhook_deliveries_due ON webhook_deliveries (next_attempt_at)
+    WHERE next_attempt_at IS NOT NULL;
+
+-- One-time voting links for invitation-only polls.
+-- Only a keyed hash of each token is stored, and not who it was sent to, so ballots can't be traced to voters.
+CREATE TABLE invitations (
+    poll_id TEXT NOT NULL REFERENCES polls(id),
+    token_hash TEXT NOT NULL,
+    used BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY (poll_id, token_hash)
+);
+
+-- Every email that has been or will be sent.
+-- Bodies are emptied once they've been sent, since invitations contain voting links.
+CREATE TABLE emails (
+    id BIGSERIAL PRIMARY KEY,
+    poll_id TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    body TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    -- NULL once the email has been sent or there are no attempts left
+    next_attempt_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    sent_at TIMESTAMP WITH TIME ZONE,
+    last_error TEXT
+);
+
+CREATE INDEX emails_due ON emails (next_attempt_at) WHERE next_attempt_at IS NOT NULL;
+
 CREATE TABLE db_info (
     version INTEGER NOT NULL
 );
 
-INSERT INTO db_info (version) VALUES (1);
+INSERT INTO db_info (version) VALUES (10);
 
 COMMIT;
@@ -1,101 +1,383 @@
 //! BetterPoll API endpoints
 
-use std::net::IpAddr;
+use std::time::Duration;
 
+use rocket::data::{ByteUnit, Data};
+use rocket::http::ContentType;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::stream::{Event, EventStream};
 use rocket::serde::json::{json, Json, Value};
 use rocket::serde::Deserialize;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast;
+use rocket::tokio::time::sleep;
+use rocket::{Shutdown, State};
 
+use crate::blt;
+use crate::config::Config;
 use crate::database::postgres::PostgresConnection;
-use crate::error::ErrorKind;
-use crate::poll::{Poll, RankedChoiceVote};
-
+use crate::directory;
+use crate::email;
+use crate::error::{ErrorKind, InternalError, VisibleError};
+use crate::events::{PollEvents, Server};
+use crate::export;
+use crate::ip::{network_prefix, ClientIp};
+use crate::logging::{self, Level};
+use crate::metrics::{self, TimedConnection};
+use crate::poll::{
+    hash_invitation, new_secret_token, winner_names, BallotReceipt, Poll, ProvisionalResults,
+    RankedChoiceVote, MAX_CANDIDATES,
+};
+use crate::pow::{self, SpentChallenges};
+use crate::ratelimit::RateLimited;
+use crate::webhooks;
 
 /// Returns all the routes that should be made available
 pub fn routes() -> Vec<rocket::Route> {
-    routes![vote, create, poll_info, status]
+    routes![
+        vote,
+        change_vote,
+        retract_vote,
+        receipts,
+        ballots,
+        poll_challenge,
+        create,
+        create_blt,
+        create_challenge,
+        poll_info,
+        events,
+        add_webhook,
+        list_webhooks,
+        list_polls,
+        status
+    ]
+}
+
+/// Gets a poll from the database, failing if it doesn't exist
+async fn find_poll(conn: &mut PostgresConnection, pollid: &str) -> Result<Poll, ErrorKind> {
+    match conn.get_poll_by_id(pollid.to_string()).await? {
+        Some(poll) => Ok(poll),
+        None => Err(VisibleError::PollNotFound(pollid.to_string()).into()),
+    }
 }
 
-fn handle_error(e: ErrorKind) -> Value {
-    match e {
-        ErrorKind::Internal(e) => {
-            eprintln!("An error occured: {:?}", e);
-            eprintln!("{:?}", backtrace::Backtrace::new());
-            json!({ "error": "Sorry, an internal server error occured. The server's administrators have been notified.", "success": false })
+/// The poll admin token sent in the `X-Admin-Token` header, if any
+struct AdminToken(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = request.headers().get_one("X-Admin-Token").map(String::from);
+        Outcome::Success(AdminToken(token))
+    }
+}
+
+/// The current UNIX timestamp, in seconds
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Checks a proof-of-work solution for `scope`
+fn check_proof_of_work(
+    config: &Config,
+    spent: &SpentChallenges,
+    scope: &str,
+    challenge: Option<&str>,
+    solution: Option<&str>,
+) -> Result<(), VisibleError> {
+    if config.pow_difficulty == 0 {
+        return Ok(());
+    }
+    let (challenge, solution) = match (challenge, solution) {
+        (Some(challenge), Some(solution)) => (challenge, solution),
+        _ => {
+            return Err(VisibleError::InvalidProofOfWork(
+                "A solution to a proof-of-work challenge is required.",
+            ))
         }
+    };
+
+    let now = unix_time();
+    let expiry = pow::verify(
+        &config.server_secret,
+        scope,
+        challenge,
+        solution,
+        config.pow_difficulty,
+        now,
+    )
+    .map_err(VisibleError::InvalidProofOfWork)?;
+    if !spent.spend(challenge, expiry, now) {
+        return Err(VisibleError::InvalidProofOfWork(
+            "This proof-of-work challenge has already been used; please try again.",
+        ));
     }
+    Ok(())
+}
+
+/// Issues a new proof-of-work challenge for `scope`
+fn challenge_response(config: &Config, scope: &str) -> Value {
+    json!({
+        "success": true,
+        "challenge": pow::new_challenge(&config.server_secret, scope, unix_time()),
+        "difficulty": config.pow_difficulty,
+    })
+}
+
+#[get("/poll/<pollid>/challenge")]
+fn poll_challenge(config: &State<Config>, pollid: &str) -> Value {
+    challenge_response(config, pollid)
+}
+
+#[get("/create/challenge")]
+fn create_challenge(config: &State<Config>) -> Value {
+    challenge_response(config, pow::CREATE_SCOPE)
 }
 
 #[derive(Deserialize)]
 struct VoteAPIRequestData {
     pub choices: Vec<String>,
+    pub challenge: Option<String>,
+    pub solution: Option<String>,
+    /// The token from the voter's invitation, for invitation-only polls
+    pub invite: Option<String>,
 }
 #[post("/poll/<pollid>/vote", data = "<data>")]
+// Route arguments are request guards and managed state that Rocket fills in
+#[allow(clippy::too_many_arguments)]
 async fn vote(
-    mut conn: PostgresConnection,
+    _limit: RateLimited,
+    mut conn: TimedConnection,
+    config: &State<Config>,
+    spent_challenges: &State<SpentChallenges>,
+    poll_events: &State<PollEvents>,
     pollid: String,
     data: Json<VoteAPIRequestData>,
-    remote_addr: Option<IpAddr>,
-) -> Value {
+    client_ip: Option<ClientIp>,
+) -> Result<Value, ErrorKind> {
     let Json(request) = data;
-    let voter_ip = match remote_addr {
-        Some(ip) => ip,
-        None => {
-            return json!({ "error": "No IP address could be determined from the request.", "success": false });
-        }
+    let voter_ip = match client_ip {
+        Some(ClientIp(ip)) => ip,
+        None => return Err(VisibleError::NoClientIp.into()),
     };
 
     let vote = RankedChoiceVote {
         ranked_choices: request.choices,
-        voter_ip,
+        voter_ip_hash: Some(RankedChoiceVote::hash_ip(
+            &config.server_secret,
+            network_prefix(
+                voter_ip,
+                config.ipv4_prefix_length,
+                config.ipv6_prefix_length,
+            ),
+        )),
     };
 
-    let poll = match conn.get_poll_by_id(pollid.clone()).await {
-        Ok(Some(poll)) => poll,
-        Ok(None) => {
-            return json!({
-                "error": format!("No poll was found with the ID '{}'.", pollid),
-                "success": false,
-            })
+    let poll = find_poll(&mut conn, &pollid).await?;
+    if poll.has_ended() {
+        return Err(VisibleError::PollEnded.into());
+    }
+
+    validate_choices(&poll, &vote.ranked_choices)?;
+
+    // IP protection and invitations already stop one client from voting many times
+    if !poll.prohibit_double_vote_by_ip && !poll.invite_only {
+        check_proof_of_work(
+            config,
+            spent_challenges,
+            &pollid,
+            request.challenge.as_deref(),
+            request.solution.as_deref(),
+        )?;
+    }
+
+    if poll.prohibit_double_vote_by_ip
+        && poll
+            .votes
+            .iter()
+            .any(|v| v.voter_ip_hash == vote.voter_ip_hash)
+    {
+        return Err(VisibleError::AlreadyVoted.into());
+    }
+
+    let receipt = BallotReceipt::new(&config.server_secret, &pollid, &vote.ranked_choices);
+    let receipt_hash = receipt.hash.clone();
+    let token = if poll.invite_only {
+        let invitation = match &request.invite {
+            Some(invite) => hash_invitation(&config.server_secret, &pollid, invite),
+            None => return Err(VisibleError::InvalidInvitation.into()),
+        };
+        // The invitation is what stops double voting, so the voter's IP address isn't needed
+        let vote = RankedChoiceVote {
+            voter_ip_hash: None,
+            ..vote
+        };
+        match conn
+            .add_invited_vote(pollid.clone(), vote, receipt, invitation)
+            .await?
+        {
+            Some(token) => token,
+            None => return Err(VisibleError::InvalidInvitation.into()),
+        }
+    } else {
+        conn.add_vote_to_poll(pollid.clone(), vote, Some(receipt))
+            .await?
+    };
+    metrics::VOTES_CAST.inc();
+    poll_events.ballots_changed(&pollid);
+
+    // The ballot has been counted, so a failure here shouldn't stop the voter getting their token.
+    // Ballots are counted again, since others may have been cast since the poll was loaded.
+    let queued = match conn.count_votes(pollid.clone()).await {
+        Ok(num_votes) => {
+            let payload = webhooks::payload(
+                webhooks::VOTE_CAST,
+                &pollid,
+                json!({ "numVotes": num_votes }),
+            );
+            conn.queue_webhook_event(pollid.clone(), webhooks::VOTE_CAST, payload.to_string())
+                .await
         }
-        Err(e) => return handle_error(e),
+        Err(e) => Err(e),
     };
+    if let Err(e) = queued {
+        logging::log(
+            Level::Error,
+            "Could not queue webhooks for a vote.",
+            json!({ "poll_id": pollid, "error": format!("{:?}", e) }),
+        );
+    }
+    Ok(json!({ "success": true, "token": token, "receipt": receipt_hash }))
+}
 
-    if vote.ranked_choices.is_empty() || vote.ranked_choices.len() > poll.candidates.len() {
-        return json!({
-            "error": format!("You must vote for between 1 and {} candidates", poll.candidates.len()),
-            "success": false,
-        });
+/// Checks that a ballot's choices are valid for the poll
+fn validate_choices(poll: &Poll, choices: &[String]) -> Result<(), VisibleError> {
+    if choices.is_empty() || choices.len() > poll.candidates.len() {
+        return Err(VisibleError::InvalidBallot(format!(
+            "You must vote for between 1 and {} candidates",
+            poll.candidates.len()
+        )));
     }
 
     let mut seen_choices = std::collections::HashSet::new();
-    for choice in &vote.ranked_choices {
+    for choice in choices {
         if seen_choices.contains(choice) {
-            return json!({
-                "error": "You cannot vote for the same candidate more than once.",
-                "success": false,
-            });
+            return Err(VisibleError::InvalidBallot(
+                "You cannot vote for the same candidate more than once.".to_string(),
+            ));
         }
         seen_choices.insert(choice.clone());
 
         if !poll.candidates.contains(choice) {
-            return json!({
-                "error": format!("The choice '{}' is not a valid choice.", choice),
-                "success": false,
-            });
+            return Err(VisibleError::InvalidBallot(format!(
+                "The choice '{}' is not a valid choice.",
+                choice
+            )));
         }
     }
 
-    if poll.prohibit_double_vote_by_ip && poll.votes.iter().any(|v| v.voter_ip == vote.voter_ip) {
-        return json!({
-            "error": "You have already voted in this poll.",
-            "success": false,
-        });
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ChangeVoteAPIRequestData {
+    pub token: String,
+    /// `None` retracts the ballot
+    pub choices: Option<Vec<String>>,
+}
+
+/// Shared implementation of the `change_vote` and `retract_vote` endpoints
+async fn change_or_retract_vote(
+    conn: &mut PostgresConnection,
+    config: &Config,
+    poll_events: &PollEvents,
+    pollid: String,
+    token: String,
+    new_choices: Option<Vec<String>>,
+) -> Result<Value, ErrorKind> {
+    let poll = find_poll(conn, &pollid).await?;
+    if poll.has_ended() {
+        return Err(VisibleError::PollEnded.into());
     }
 
-    match conn.add_vote_to_poll(pollid, vote).await {
-        Ok(_) => json!({ "success": true }),
-        Err(e) => handle_error(e),
+    let new_ballot = match new_choices {
+        Some(choices) => {
+            validate_choices(&poll, &choices)?;
+            let receipt = BallotReceipt::new(&config.server_secret, &pollid, &choices);
+            Some((choices, receipt))
+        }
+        None => None,
+    };
+    let receipt_hash = new_ballot.as_ref().map(|(_, receipt)| receipt.hash.clone());
+
+    if !conn.change_vote(pollid.clone(), token, new_ballot).await? {
+        return Err(VisibleError::BallotNotFound.into());
     }
+    poll_events.ballots_changed(&pollid);
+    Ok(match receipt_hash {
+        Some(receipt) => json!({ "success": true, "receipt": receipt }),
+        None => json!({ "success": true }),
+    })
+}
+
+#[post("/poll/<pollid>/vote/change", data = "<data>")]
+async fn change_vote(
+    mut conn: TimedConnection,
+    config: &State<Config>,
+    poll_events: &State<PollEvents>,
+    pollid: String,
+    data: Json<ChangeVoteAPIRequestData>,
+) -> Result<Value, ErrorKind> {
+    let Json(request) = data;
+    let choices = match request.choices {
+        Some(choices) => choices,
+        None => return Err(VisibleError::InvalidBallot(
+            "You must provide your new choices. To withdraw your ballot, use the retract endpoint."
+                .to_string(),
+        )
+        .into()),
+    };
+
+    change_or_retract_vote(
+        &mut conn,
+        config,
+        poll_events,
+        pollid,
+        request.token,
+        Some(choices),
+    )
+    .await
+}
+
+#[post("/poll/<pollid>/vote/retract", data = "<data>")]
+async fn retract_vote(
+    mut conn: TimedConnection,
+    config: &State<Config>,
+    poll_events: &State<PollEvents>,
+    pollid: String,
+    data: Json<ChangeVoteAPIRequestData>,
+) -> Result<Value, ErrorKind> {
+    let Json(request) = data;
+    change_or_retract_vote(&mut conn, config, poll_events, pollid, request.token, None).await
+}
+
+#[get("/poll/<pollid>/receipts")]
+async fn receipts(mut conn: TimedConnection, pollid: String) -> Result<Value, ErrorKind> {
+    let poll = find_poll(&mut conn, &pollid).await?;
+    if !poll.has_ended() {
+        return Err(VisibleError::PollNotEnded(
+            "Receipts are only published once the poll has ended.",
+        )
+        .into());
+    }
+
+    let receipts = conn.get_receipts(pollid).await?;
+    Ok(json!({ "success": true, "receipts": receipts }))
 }
 
 #[derive(Deserialize)]
@@ -109,65 +391,78 @@ pub struct CreateAPIRequestData<'a> {
     pub num_winners: i64,
     pub id: Option<&'a str>,
     pub protection: Option<&'a str>,
+    #[serde(rename = "provisionalResults")]
+    pub provisional_results: Option<&'a str>,
+    pub listed: Option<bool>,
+    /// Email addresses to send one-time voting links to, for invitation-only polls
+    pub invitees: Option<Vec<String>>,
+    /// Where to email the results when the poll ends
+    #[serde(rename = "creatorEmail")]
+    pub creator_email: Option<String>,
+    pub challenge: Option<&'a str>,
+    pub solution: Option<&'a str>,
 }
 
 #[post("/create", data = "<data>")]
 async fn create(
-    mut conn: PostgresConnection,
+    _limit: RateLimited,
+    mut conn: TimedConnection,
+    config: &State<Config>,
+    spent_challenges: &State<SpentChallenges>,
     data: Json<CreateAPIRequestData<'_>>,
-) -> Value {
+) -> Result<Value, ErrorKind> {
     let Json(request) = data;
+    check_proof_of_work(
+        config,
+        spent_challenges,
+        pow::CREATE_SCOPE,
+        request.challenge,
+        request.solution,
+    )?;
+    create_poll(&mut conn, config, request, vec![]).await
+}
+
+/// Validates a request to create a poll, then creates it with the given ballots already cast
+async fn create_poll(
+    conn: &mut PostgresConnection,
+    config: &Config,
+    request: CreateAPIRequestData<'_>,
+    votes: Vec<RankedChoiceVote>,
+) -> Result<Value, ErrorKind> {
+    let invalid = |message: &str| -> Result<Value, ErrorKind> {
+        Err(VisibleError::InvalidPoll(message.to_string()).into())
+    };
 
     // Validate candidates
-    if request.candidates.len() < 2 || request.candidates.len() > 1024 {
-        return json!({
-            "error": "The number of candidates must be between 2 and 1,024.",
-            "success": false,
-        });
+    if request.candidates.len() < 2 || request.candidates.len() > MAX_CANDIDATES {
+        return invalid("The number of candidates must be between 2 and 1,024.");
     }
     for candidate in &request.candidates {
         if candidate.len() > 1024 {
-            return json!({
-                "error": "A candidate's name must be less than 1,024 characters.",
-                "success": false,
-            });
+            return invalid("A candidate's name must be less than 1,024 characters.");
         }
         if candidate.trim().is_empty() {
-            return json!({
-                "error": "A candidate's name must not be empty.",
-                "success": false,
-            });
+            return invalid("A candidate's name must not be empty.");
         }
     }
 
     // Validate duration
     if request.duration < 1 {
-        return json!({
-            "error": "The duration must be a positive, nonzero number.",
-            "success": false,
-        });
+        return invalid("The duration must be a positive, nonzero number.");
     }
     let duration = std::time::Duration::from_secs(request.duration as u64);
 
     // Validate numWinners
     if request.num_winners <= 0 {
-        return json!({
-            "error": "The number of winners must be a positive, nonzero number.",
-            "success": false,
-        });
+        return invalid("The number of winners must be a positive, nonzero number.");
     }
     if request.num_winners >= request.candidates.len() as i64 {
-        return json!({
-            "error": "The number of winners must be less than to the number of candidates.",
-            "success": false,
-        });
+        return invalid("The number of winners must be less than to the number of candidates.");
     }
     let num_winners: usize = match request.num_winners.try_into() {
         Ok(n) => n,
         Err(e) => {
-            eprintln!("An error occured: {:?}", e);
-            eprintln!("{:?}", backtrace::Backtrace::new());
-            return json!({ "error": "Sorry, an internal server error occured. The server's administrators have been notified.", "success": false });
+            return Err(InternalError::InvalidNumWinners(request.num_winners as i32, e).into())
         }
     };
 
@@ -175,31 +470,20 @@ async fn create(
     let id = match request.id {
         Some(id) => {
             if id.is_empty() || id.len() > 32 {
-                return json!({
-                    "error": "The ID must be between 1 and 32 characters.",
-                    "success": false,
-                });
+                return invalid("The ID must be between 1 and 32 characters.");
             }
             if id
                 .chars()
                 .any(|c| !c.is_ascii_alphanumeric() && c != '_' && c != '.' && c != '-')
             {
-                return json!({
-                    "error": "The ID must only contain ASCII alphanumeric characters, '-', '.', and '-'.",
-                    "success": false,
-                });
+                return invalid(
+                    "The ID must only contain ASCII alphanumeric characters, '-', '.', and '-'.",
+                );
             }
 
-            match conn.get_poll_by_id(id.to_string()).await {
-                Ok(Some(_poll)) => {
-                    return json!({
-                        "error": format!("A poll already exists with the URL '{}'.", id),
-                        "success": false,
-                    })
-                }
-                Ok(None) => {}
-                Err(e) => return handle_error(e),
-            };
+            if conn.get_poll_by_id(id.to_string()).await?.is_some() {
+                return Err(VisibleError::PollIdTaken(id.to_string()).into());
+            }
 
             Some(id.to_string())
         }
@@ -207,35 +491,69 @@ async fn create(
     };
 
     // Validate protection
-    let protection = match request.protection {
-        Some("ip") => true,
-        Some("none") => false,
-        Some(_) => {
-            return json!({
-                "error": "The protection must be either 'ip' or 'none'.",
-                "success": false,
-            })
+    let (protection, invite_only) = match request.protection {
+        Some("ip") => (true, false),
+        Some("invite") => (false, true),
+        Some("none") => (false, false),
+        Some(_) => return invalid("The protection must be 'ip', 'invite', or 'none'."),
+        None => (false, false),
+    };
+
+    // Validate invitees
+    let mut invitees = request.invitees.unwrap_or_default();
+    invitees.sort_unstable();
+    invitees.dedup();
+    if invite_only {
+        if config.smtp.is_none() {
+            return invalid("This server can't send email, so polls can't be invitation-only.");
+        }
+        if invitees.is_empty() || invitees.len() > email::MAX_INVITEES {
+            return invalid("Invitation-only polls must invite between 1 and 1,000 people.");
+        }
+        for invitee in &invitees {
+            if let Err(message) = email::validate_address(invitee) {
+                return invalid(&message);
+            }
+        }
+    } else if !invitees.is_empty() {
+        return invalid("People can only be invited to polls with 'invite' protection.");
+    }
+
+    // Validate creator email
+    if let Some(creator_email) = &request.creator_email {
+        if config.smtp.is_none() {
+            return invalid("This server can't send email, so the results can't be emailed.");
+        }
+        if let Err(message) = email::validate_address(creator_email) {
+            return invalid(&message);
+        }
+    }
+
+    // Validate provisional results
+    let provisional_results = match request.provisional_results {
+        Some(name) => ProvisionalResults::from_name(name),
+        None => Some(ProvisionalResults::Hidden),
+    };
+    let provisional_results = match provisional_results {
+        Some(provisional_results) => provisional_results,
+        None => {
+            return invalid(
+                "Provisional results must be 'hidden', 'visible_to_admin', or 'public'.",
+            )
         }
-        None => false,
     };
 
     // Validate name
     if request.name.len() > 1024 || request.name.is_empty() {
-        return json!({
-            "error": "The name must be between 1 and 1,024 characters.",
-            "success": false,
-        });
+        return invalid("The name must be between 1 and 1,024 characters.");
     }
 
     // Validate description
     if request.description.len() > 10_000 {
-        return json!({
-            "error": "The name must be between 1 and 10,000 characters.",
-            "success": false,
-        });
+        return invalid("The name must be between 1 and 10,000 characters.");
     }
 
-    let poll = match Poll::new(
+    let mut poll = Poll::new(
         id,
         request.name,
         request.description,
@@ -243,34 +561,145 @@ async fn create(
         duration,
         num_winners,
         protection,
-    ) {
-        Ok(poll) => poll,
-        Err(e) => return handle_error(e),
-    };
+    )?;
+    poll.votes = votes;
+    poll.provisional_results = provisional_results;
+    poll.listed = request.listed.unwrap_or(false);
+    poll.invite_only = invite_only;
+    poll.creator_email = request.creator_email;
+
+    // Only a hash of each invitation's token is stored; the token itself is only in the email
+    let invitations = invitees
+        .iter()
+        .map(|invitee| {
+            let token = new_secret_token();
+            let link = format!("{}?invite={}", config.poll_url(&poll.id), token);
+            (
+                hash_invitation(&config.server_secret, &poll.id, &token),
+                email::invitation(&poll, invitee, &link),
+            )
+        })
+        .collect();
 
     let id = poll.id.clone();
-    match conn.add_poll(poll).await {
-        Ok(_) => json!({ "success": true, "id": id }),
-        Err(e) => handle_error(e),
+    let admin_token = poll.admin_token.clone();
+    conn.add_poll(poll, invitations).await?;
+    metrics::POLLS_CREATED.inc();
+    Ok(json!({ "success": true, "id": id, "adminToken": admin_token }))
+}
+
+/// The largest BLT file that can be uploaded
+const MAX_BLT_SIZE: ByteUnit = ByteUnit::Mebibyte(2);
+
+#[post(
+    "/create/blt?<duration>&<id>&<protection>&<provisional_results>&<listed>&<description>&<challenge>&<solution>",
+    data = "<data>"
+)]
+#[allow(clippy::too_many_arguments)]
+async fn create_blt(
+    _limit: RateLimited,
+    mut conn: TimedConnection,
+    config: &State<Config>,
+    spent_challenges: &State<SpentChallenges>,
+    duration: i64,
+    id: Option<&str>,
+    protection: Option<&str>,
+    provisional_results: Option<&str>,
+    listed: Option<bool>,
+    description: Option<String>,
+    challenge: Option<&str>,
+    solution: Option<&str>,
+    data: Data<'_>,
+) -> Result<Value, ErrorKind> {
+    check_proof_of_work(
+        config,
+        spent_challenges,
+        pow::CREATE_SCOPE,
+        challenge,
+        solution,
+    )?;
+
+    let blt = match data.open(MAX_BLT_SIZE).into_string().await {
+        Ok(blt) if blt.is_complete() => blt.into_inner(),
+        Ok(_) => return Err(VisibleError::BltTooLarge(MAX_BLT_SIZE).into()),
+        Err(_) => {
+            return Err(VisibleError::InvalidBlt("It must be valid UTF-8 text.".to_string()).into())
+        }
+    };
+    let election = blt::parse(&blt).map_err(VisibleError::InvalidBlt)?;
+
+    let request = CreateAPIRequestData {
+        name: election.title,
+        description: description.unwrap_or_default(),
+        candidates: election.candidates,
+        duration,
+        num_winners: election.num_seats as i64,
+        id,
+        protection,
+        provisional_results,
+        listed,
+        invitees: None,
+        creator_email: None,
+        challenge: None,
+        solution: None,
+    };
+    let votes = election
+        .ballots
+        .into_iter()
+        .map(|ranked_choices| RankedChoiceVote {
+            ranked_choices,
+            voter_ip_hash: None,
+        })
+        .collect();
+    create_poll(&mut conn, config, request, votes).await
+}
+
+#[get("/poll/<pollid>/ballots?<format>")]
+async fn ballots(
+    mut conn: TimedConnection,
+    pollid: String,
+    format: Option<&str>,
+    admin: AdminToken,
+) -> Result<(ContentType, String), ErrorKind> {
+    let poll = find_poll(&mut conn, &pollid).await?;
+    if !poll.has_ended() && !poll.is_admin(admin.0.as_deref()) {
+        return Err(VisibleError::PollNotEnded(
+            "Ballots can only be exported once the poll has ended.",
+        )
+        .into());
+    }
+
+    let ballots = poll.anonymised_ballots();
+    match format {
+        Some("json") | None => Ok((
+            ContentType::JSON,
+            json!({
+                "success": true,
+                "candidates": poll.candidates,
+                "ballots": ballots,
+            })
+            .to_string(),
+        )),
+        Some("csv") => Ok((ContentType::CSV, export::ballots_to_csv(&poll, &ballots)?)),
+        Some("blt") => Ok((ContentType::Plain, blt::write(&poll))),
+        Some(_) => Err(VisibleError::InvalidFormat.into()),
     }
 }
 
-#[get("/poll/<pollid>")]
+/// Ranked below `GET /poll/<pollid>.ics`, which needs to see segments ending in `.ics` first
+#[get("/poll/<pollid>", rank = 2)]
 async fn poll_info(
-    mut conn: PostgresConnection,
+    mut conn: TimedConnection,
     pollid: String,
-) -> Value {
-    let poll = match conn.get_poll_by_id(pollid.clone()).await {
-        Ok(Some(poll)) => poll,
-        Ok(None) => {
-            return json!({
-                "error": format!("No poll was found with the ID '{}'.", pollid),
-                "success": false,
-            })
-        }
-        Err(e) => return handle_error(e),
-    };
+    admin: AdminToken,
+) -> Result<Value, ErrorKind> {
+    let poll = find_poll(&mut conn, &pollid).await?;
+    let is_admin = poll.is_admin(admin.0.as_deref());
+    poll_info_json(poll, is_admin)
+}
 
+/// Describes a poll, including its winners if it has ended or its standings can be shown
+pub(crate) fn poll_info_json(poll: Poll, is_admin: bool) -> Result<Value, ErrorKind> {
     let mut result = json!({
         "success": true,
         "name": poll.title,
@@ -279,63 +708,273 @@ async fn poll_info(
         "creationTime": poll.creation_time,
         "endingTime": poll.end_time,
         "numWinners": poll.num_winners,
-        "protection": if poll.prohibit_double_vote_by_ip { json!("ip") } else { Value::Null },
+        "protection": if poll.prohibit_double_vote_by_ip {
+            json!("ip")
+        } else if poll.invite_only {
+            json!("invite")
+        } else {
+            Value::Null
+        },
         "numVotes": poll.votes.len(),
+        "provisionalResults": poll.provisional_results.name(),
+        "listed": poll.listed,
     });
 
-    if let Some(mut winners) = poll.winners {
+    if let Some(winners) = poll.winners {
         result["ended"] = Value::Bool(true);
-        // Sort in reverse order - lowest ranks first
-        winners.sort_by(|a, b| b.rank.cmp(&a.rank));
-        let mut winners_unranked = vec![];
-        let mut cur_rank = 0;
-        while let Some(winner) = winners.pop() {
-            if winner.rank != cur_rank {
-                if winners_unranked.len() < poll.num_winners {
-                    // take the next rank
-                    cur_rank += 1;
-                } else {
-                    // We have enough winners!
-                    break;
+        result["winners"] = json!(winner_names(winners, poll.num_winners));
+        result["provisional"] = Value::Bool(false);
+    } else {
+        result["ended"] = Value::Bool(false);
+        if poll.shows_provisional_results(is_admin) {
+            result["winners"] = json!(winner_names(poll.find_winners()?, poll.num_winners));
+            result["provisional"] = Value::Bool(true);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Gets the latest version of a poll for an event stream, logging any error
+async fn reload_poll(server: &Server<'_>, pollid: &str) -> Option<Poll> {
+    let mut conn = PostgresConnection::get_one(server.0).await?;
+    match conn.get_poll_by_id(pollid.to_string()).await {
+        Ok(poll) => poll,
+        Err(e) => {
+            logging::log(
+                Level::Error,
+                "Could not reload a poll for its event stream.",
+                json!({ "poll_id": pollid, "error": format!("{:?}", e) }),
+            );
+            None
+        }
+    }
+}
+
+/// Waits until a ballot in the poll changes. Returns `false` if no more changes will be announced.
+async fn ballots_changed(receiver: &mut broadcast::Receiver<String>, pollid: &str) -> bool {
+    loop {
+        match receiver.recv().await {
+            Ok(changed) if changed == pollid => return true,
+            Ok(_) => continue,
+            // Some announcements were missed, so this poll might have changed
+            Err(broadcast::error::RecvError::Lagged(_)) => return true,
+            Err(broadcast::error::RecvError::Closed) => return false,
+        }
+    }
+}
+
+/// Streams the number of ballots as it changes, along with the provisional standings if the
+/// poll shows them. When the poll ends, a `closed` event with the winners ends the stream.
+#[get("/poll/<pollid>/events?<admin>")]
+async fn events<'r>(
+    mut conn: TimedConnection,
+    server: Server<'r>,
+    poll_events: &State<PollEvents>,
+    mut shutdown: Shutdown,
+    pollid: String,
+    admin: Option<String>,
+    admin_header: AdminToken,
+) -> Result<EventStream<impl rocket::futures::Stream<Item = Event> + 'r>, ErrorKind> {
+    // Subscribe first so that no changes are missed while the poll is loaded
+    let mut receiver = poll_events.subscribe();
+    let mut poll = find_poll(&mut conn, &pollid).await?;
+    drop(conn);
+
+    // `EventSource` can't set headers, so the admin token can also be given in the query string
+    let is_admin = poll.is_admin(admin_header.0.or(admin).as_deref());
+    let show_standings = poll.shows_provisional_results(is_admin);
+
+    Ok(EventStream! {
+        loop {
+            yield Event::json(&json!({ "numVotes": poll.votes.len() })).event("votes");
+
+            if let Some(winners) = poll.winners.take() {
+                yield Event::json(&json!({
+                    "numVotes": poll.votes.len(),
+                    "winners": winner_names(winners, poll.num_winners),
+                }))
+                .event("closed");
+                break;
+            }
+
+            if show_standings {
+                if let Ok(standings) = poll.find_winners() {
+                    yield Event::json(&json!({
+                        "provisional": true,
+                        "winners": winner_names(standings, poll.num_winners),
+                    }))
+                    .event("standings");
                 }
             }
-            winners_unranked.push(winner.candidate);
+
+            // Wake up just after the poll ends, so that the winners can be sent
+            let until_end = Duration::from_secs(poll.end_time.saturating_sub(unix_time()) + 1);
+            select! {
+                changed = ballots_changed(&mut receiver, &pollid) => if !changed { break },
+                _ = sleep(until_end) => {},
+                _ = &mut shutdown => break,
+            }
+
+            poll = match reload_poll(&server, &pollid).await {
+                Some(poll) => poll,
+                None => break,
+            };
         }
-        result["winners"] = json!(winners_unranked);
-    } else {
-        result["ended"] = Value::Bool(false);
+    })
+}
+
+#[derive(Deserialize)]
+struct WebhookAPIRequestData {
+    pub url: String,
+    /// Generated if it isn't given
+    pub secret: Option<String>,
+}
+
+#[post("/poll/<pollid>/webhooks", data = "<data>")]
+async fn add_webhook(
+    mut conn: TimedConnection,
+    config: &State<Config>,
+    pollid: String,
+    admin: AdminToken,
+    data: Json<WebhookAPIRequestData>,
+) -> Result<Value, ErrorKind> {
+    let Json(request) = data;
+    let poll = find_poll(&mut conn, &pollid).await?;
+    if !poll.is_admin(admin.0.as_deref()) {
+        return Err(VisibleError::AdminTokenRequired.into());
     }
 
-    result
+    let invalid = |message: &str| -> Result<Value, ErrorKind> {
+        Err(VisibleError::InvalidWebhook(message.to_string()).into())
+    };
+    if poll.has_ended() {
+        return invalid("Webhooks can't be added to polls that have ended.");
+    }
+    if let Err(message) = webhooks::validate_url(&request.url) {
+        return invalid(&message);
+    }
+    // This is checked again whenever the webhook is sent, in case the host's addresses change
+    if let Err(message) = webhooks::resolve(&request.url, config.allow_private_webhooks).await {
+        return invalid(&message);
+    }
+    let secret = match request.secret {
+        Some(secret) if secret.len() < 16 || secret.len() > 256 => {
+            return invalid("The secret must be between 16 and 256 characters.")
+        }
+        Some(secret) => secret,
+        None => new_secret_token(),
+    };
+    if conn.get_webhooks(pollid.clone()).await?.len() >= webhooks::MAX_WEBHOOKS_PER_POLL {
+        return invalid(&format!(
+            "A poll can't have more than {} webhooks.",
+            webhooks::MAX_WEBHOOKS_PER_POLL
+        ));
+    }
+
+    let id = conn
+        .add_webhook(pollid, request.url, secret.clone())
+        .await?;
+    Ok(json!({ "success": true, "id": id, "secret": secret }))
+}
+
+/// The most deliveries shown in a poll's webhook delivery log
+const DELIVERY_LOG_LENGTH: i64 = 100;
+
+#[get("/poll/<pollid>/webhooks")]
+async fn list_webhooks(
+    mut conn: TimedConnection,
+    pollid: String,
+    admin: AdminToken,
+) -> Result<Value, ErrorKind> {
+    let poll = find_poll(&mut conn, &pollid).await?;
+    if !poll.is_admin(admin.0.as_deref()) {
+        return Err(VisibleError::AdminTokenRequired.into());
+    }
+
+    let webhooks: Vec<Value> = conn
+        .get_webhooks(pollid.clone())
+        .await?
+        .iter()
+        .map(|webhook| {
+            json!({
+                "id": webhook.id,
+                "url": webhook.url,
+                "createdAt": webhook.created_at,
+            })
+        })
+        .collect();
+    let deliveries: Vec<Value> = conn
+        .get_webhook_deliveries(pollid, DELIVERY_LOG_LENGTH)
+        .await?
+        .iter()
+        .map(webhooks::delivery_json)
+        .collect();
+    Ok(json!({ "success": true, "webhooks": webhooks, "deliveries": deliveries }))
+}
+
+#[get("/polls?<q>&<status>&<sort>&<cursor>&<limit>")]
+async fn list_polls(
+    mut conn: TimedConnection,
+    q: Option<&str>,
+    status: Option<&str>,
+    sort: Option<&str>,
+    cursor: Option<&str>,
+    limit: Option<i64>,
+) -> Result<Value, ErrorKind> {
+    let query = directory::Query::parse(q, status, sort, cursor, limit)?;
+    let page_size = query.limit as usize;
+    let polls = conn.get_listed_polls(query).await?;
+
+    // A full page might not be the last one
+    let next_cursor = match polls.last() {
+        Some(poll) if polls.len() == page_size => Some(poll.cursor.encode()),
+        _ => None,
+    };
+    let polls: Vec<Value> = polls
+        .iter()
+        .map(|poll| {
+            json!({
+                "id": poll.id,
+                "name": poll.title,
+                "endingTime": poll.end_time,
+                "numVotes": poll.num_votes,
+            })
+        })
+        .collect();
+    Ok(json!({ "success": true, "polls": polls, "nextCursor": next_cursor }))
 }
 
 #[get("/status")]
-async fn status(mut conn: PostgresConnection) -> Value {
-    json!({
+async fn status(conn: Option<TimedConnection>) -> Result<Value, ErrorKind> {
+    let mut conn = conn.ok_or(VisibleError::DatabaseUnavailable)?;
+    Ok(json!({
         "success": true,
-        "total": match conn.get_total_polls().await {
-            Ok(n) => n.into(),
-            Err(_) => Value::Null,
-        },
-        "active": match conn.get_active_polls().await {
-            Ok(n) => n.into(),
-            Err(_) => Value::Null,
-        },
-    })
+        "total": conn.get_total_polls().await?,
+        "active": conn.get_active_polls().await?,
+    }))
 }
 
 #[cfg(test)]
 mod tests {
-    use std::net::ToSocketAddrs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, ToSocketAddrs};
+    use std::sync::mpsc;
 
     use postgres::NoTls;
 
-    use rocket::http::Status;
+    use rocket::http::{ContentType, Header, Status};
     use rocket::local::blocking::Client;
     use rocket::serde::json::{json, Value};
 
     use serial_test::serial;
 
+    use crate::config::Config;
+    use crate::crypto;
+    use crate::database::postgres::{self as db, PostgresConnection};
+    use crate::email;
+    use crate::webhooks;
+
     fn create_client() -> Client {
         Client::tracked(crate::rocket()).expect("valid rocket instance")
     }
@@ -346,8 +985,8 @@ mod tests {
         };
     }
 
-    /// Clears the database (so that unit tests don't interfere with each other)
-    fn clear_db(client: &Client) {
+    /// Connects directly to the test database
+    fn connect_to_db(client: &Client) -> postgres::Client {
         let db_url = client
             .rocket()
             .figment()
@@ -355,13 +994,24 @@ mod tests {
             .expect("No 'test_db' configured in Rocket.toml");
         let db_url = db_url.as_str().unwrap();
 
-        let mut conn = postgres::Client::connect(db_url, NoTls).expect("bad database connection");
+        postgres::Client::connect(db_url, NoTls).expect("bad database connection")
+    }
 
+    /// Clears the database (so that unit tests don't interfere with each other)
+    fn clear_db(client: &Client) {
+        let mut conn = connect_to_db(client);
+
+        conn.execute("DELETE FROM vote_changes CASCADE", &[])
+            .unwrap();
         conn.execute("DELETE FROM votes CASCADE", &[]).unwrap();
+        conn.execute("DELETE FROM webhook_deliveries", &[]).unwrap();
+        conn.execute("DELETE FROM webhooks", &[]).unwrap();
+        conn.execute("DELETE FROM emails", &[]).unwrap();
+        conn.execute("DELETE FROM invitations", &[]).unwrap();
         conn.execute("DELETE FROM polls CASCADE", &[]).unwrap();
     }
 
-    fn post(client: &Client, path: &str, data: Value) {
+    fn post(client: &Client, path: &str, data: Value) -> Value {
         let mut req = client.post(path);
         req.set_remote(localhost_ip!());
         let req = req.json(&data).dispatch();
@@ -369,6 +1019,7 @@ mod tests {
 
         let json = req.into_json::<Value>().unwrap();
         assert_eq!(json["success"], true, "no success: {:?}", json);
+        json
     }
 
     #[test]
@@ -454,6 +1105,7 @@ mod tests {
             dbg!(bad_json, &json);
             assert_eq!(json["success"], false);
             assert!(!json["error"].as_str().unwrap().is_empty());
+            assert_eq!(json["code"], "invalid_ballot");
         }
     }
 
@@ -473,6 +1125,7 @@ mod tests {
             .unwrap();
         assert_eq!(json["success"], false);
         assert!(!json["error"].as_str().unwrap().is_empty());
+        assert_eq!(json["code"], "poll_not_found");
     }
 
     #[test]
@@ -512,6 +1165,7 @@ mod tests {
             .unwrap();
         assert_eq!(json_after_expiry["success"], false);
         assert!(!json_after_expiry["error"].as_str().unwrap().is_empty());
+        assert_eq!(json_after_expiry["code"], "poll_ended");
     }
 
     #[test]
@@ -535,19 +1189,678 @@ mod tests {
             }),
         );
 
-        let mut req = client.post("/poll/vote_ip_duplicate/vote");
+        let mut req = client.post("/poll/vote_ip_duplicate/vote");
+        req.set_remote(localhost_ip!());
+        let json = req
+            .json(&json!({ "choices": ["A"] }))
+            .dispatch()
+            .into_json::<Value>()
+            .unwrap();
+        assert_eq!(json["success"], true);
+
+        let mut req = client.post("/poll/vote_ip_duplicate/vote");
+        req.set_remote(localhost_ip!());
+        let json = req
+            .json(&json!({ "choices": ["A", "B", "C", "D"] }))
+            .dispatch()
+            .into_json::<Value>()
+            .unwrap();
+        assert_eq!(json["success"], false);
+        assert!(!json["error"].as_str().unwrap().is_empty());
+        assert_eq!(json["code"], "already_voted");
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn vote_ignores_untrusted_forwarded_for() {
+        let client = create_client();
+        clear_db(&client);
+
+        post(
+            &client,
+            "/create",
+            json!({
+                "name": "Voting Test - Forged X-Forwarded-For",
+                "description": "the test client isn't a trusted proxy",
+                "candidates": ["A", "B"],
+                "duration": 10000i32,
+                "numWinners": 1i32,
+                "id": "vote_forwarded_for",
+                "protection": "ip",
+            }),
+        );
+
+        for (forwarded_for, should_succeed) in [("192.0.2.1", true), ("192.0.2.2", false)] {
+            let mut req = client
+                .post("/poll/vote_forwarded_for/vote")
+                .header(Header::new("X-Forwarded-For", forwarded_for));
+            req.set_remote(localhost_ip!());
+            let json = req
+                .json(&json!({ "choices": ["A"] }))
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap();
+            assert_eq!(json["success"], should_succeed);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn vote_rate_limited() {
+        let client = create_client();
+        clear_db(&client);
+
+        post(
+            &client,
+            "/create",
+            json!({
+                "name": "Voting Test - Rate Limit",
+                "description": "one client can't vote too often",
+                "candidates": ["A", "B"],
+                "duration": 10000i32,
+                "numWinners": 1i32,
+                "id": "vote_rate_limit",
+            }),
+        );
+
+        // The default limit for voting is a burst of 20
+        for _ in 0..20 {
+            post(
+                &client,
+                "/poll/vote_rate_limit/vote",
+                json!({ "choices": ["A"] }),
+            );
+        }
+
+        let mut req = client.post("/poll/vote_rate_limit/vote");
+        req.set_remote(localhost_ip!());
+        let res = req.json(&json!({ "choices": ["A"] })).dispatch();
+        assert_eq!(res.status(), Status::TooManyRequests);
+        assert_eq!(res.into_json::<Value>().unwrap()["success"], false);
+
+        // Other clients aren't affected
+        let mut req = client.post("/poll/vote_rate_limit/vote");
+        req.set_remote("192.0.2.1:49124".to_socket_addrs().unwrap().next().unwrap());
+        let res = req.json(&json!({ "choices": ["A"] })).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    /// Fetches a proof-of-work challenge from `path` and solves it
+    fn solve_challenge(client: &Client, path: &str) -> (String, String) {
+        use sha2::{Digest, Sha256};
+
+        let json = client.get(path).dispatch().into_json::<Value>().unwrap();
+        let challenge = json["challenge"].as_str().unwrap().to_string();
+        let difficulty = json["difficulty"].as_u64().unwrap();
+        let solution = (0u64..)
+            .map(|n| n.to_string())
+            .find(|solution| {
+                let hash = Sha256::digest(format!("{}:{}", challenge, solution).as_bytes());
+                let hash = u128::from_be_bytes(hash[..16].try_into().unwrap());
+                hash.leading_zeros() as u64 >= difficulty
+            })
+            .unwrap();
+        (challenge, solution)
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn vote_proof_of_work() {
+        let rocket = crate::rocket();
+        let figment = rocket.figment().clone().merge(("pow_difficulty", 4));
+        let client = Client::tracked(rocket.configure(figment)).expect("valid rocket instance");
+        clear_db(&client);
+
+        let (challenge, solution) = solve_challenge(&client, "/create/challenge");
+        post(
+            &client,
+            "/create",
+            json!({
+                "name": "Voting Test - Proof of Work",
+                "description": "voting needs a proof of work",
+                "candidates": ["A", "B"],
+                "duration": 10000i32,
+                "numWinners": 1i32,
+                "id": "vote_pow",
+                "challenge": challenge,
+                "solution": solution,
+            }),
+        );
+
+        let vote = |data: Value| {
+            let mut req = client.post("/poll/vote_pow/vote");
+            req.set_remote(localhost_ip!());
+            req.json(&data).dispatch().into_json::<Value>().unwrap()
+        };
+
+        // No solution
+        assert_eq!(vote(json!({ "choices": ["A"] }))["success"], false);
+
+        // A challenge for a different poll
+        let (challenge, solution) = solve_challenge(&client, "/poll/other_poll/challenge");
+        let json = vote(json!({ "choices": ["A"], "challenge": challenge, "solution": solution }));
+        assert_eq!(json["success"], false);
+
+        // Each challenge can only be used once
+        let (challenge, solution) = solve_challenge(&client, "/poll/vote_pow/challenge");
+        let json = vote(json!({ "choices": ["A"], "challenge": challenge, "solution": solution }));
+        assert_eq!(json["success"], true, "no success: {:?}", json);
+        let json = vote(json!({ "choices": ["B"], "challenge": challenge, "solution": solution }));
+        assert_eq!(json["success"], false);
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn vote_ip_not_stored() {
+        let client = create_client();
+        clear_db(&client);
+
+        post(
+            &client,
+            "/create",
+            json!({
+                "name": "Voting Test - IP Privacy",
+                "description": "this poll shouldn't store IP addresses",
+                "candidates": ["A", "B"],
+                "duration": 10000i32,
+                "numWinners": 1i32,
+                "id": "vote_ip_privacy",
+                "protection": "ip",
+            }),
+        );
+        post(
+            &client,
+            "/poll/vote_ip_privacy/vote",
+            json!({ "choices": ["A"] }),
+        );
+
+        let mut conn = connect_to_db(&client);
+        let row = conn
+            .query_one(
+                "SELECT voter_ip_hash FROM votes WHERE poll_id = 'vote_ip_privacy'",
+                &[],
+            )
+            .unwrap();
+        let hash: String = row.get("voter_ip_hash");
+        assert_eq!(hash.len(), 64);
+        assert!(!hash.contains("127.0.0.1"));
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn vote_change_and_retract() {
+        let client = create_client();
+        clear_db(&client);
+
+        fn get_poll_info(c: &Client, id: &str) -> Value {
+            let mut req = c.get(format!("/poll/{}", id));
+            req.set_remote(localhost_ip!());
+            req.dispatch().into_json::<Value>().unwrap()
+        }
+        post(
+            &client,
+            "/create",
+            json!({
+                "name": "Voting Test - Change and Retract",
+                "description": "Ballots in this poll will be changed and retracted.",
+                "candidates": ["A", "B", "C"],
+                "duration": 10000i32,
+                "numWinners": 1i32,
+                "id": "vote_change",
+                "protection": "ip",
+            }),
+        );
+
+        let vote_json = post(
+            &client,
+            "/poll/vote_change/vote",
+            json!({ "choices": ["A", "B"] }),
+        );
+        let token = vote_json["token"].as_str().unwrap();
+        assert!(!token.is_empty());
+        assert_eq!(get_poll_info(&client, "vote_change")["numVotes"], 1i32);
+
+        post(
+            &client,
+            "/poll/vote_change/vote/change",
+            json!({ "token": token, "choices": ["C"] }),
+        );
+        assert_eq!(get_poll_info(&client, "vote_change")["numVotes"], 1i32);
+
+        // Invalid choices are rejected just like when voting
+        let mut req = client.post("/poll/vote_change/vote/change");
+        req.set_remote(localhost_ip!());
+        let json = req
+            .json(&json!({ "token": token, "choices": ["C", "C"] }))
+            .dispatch()
+            .into_json::<Value>()
+            .unwrap();
+        assert_eq!(json["success"], false);
+
+        post(
+            &client,
+            "/poll/vote_change/vote/retract",
+            json!({ "token": token }),
+        );
+        assert_eq!(get_poll_info(&client, "vote_change")["numVotes"], 0i32);
+
+        // The token can't be used once the ballot has been retracted
+        let mut req = client.post("/poll/vote_change/vote/retract");
+        req.set_remote(localhost_ip!());
+        let json = req
+            .json(&json!({ "token": token }))
+            .dispatch()
+            .into_json::<Value>()
+            .unwrap();
+        assert_eq!(json["success"], false);
+        assert!(!json["error"].as_str().unwrap().is_empty());
+
+        // After retracting, the voter can vote again, even with IP protection
+        post(
+            &client,
+            "/poll/vote_change/vote",
+            json!({ "choices": ["B"] }),
+        );
+        assert_eq!(get_poll_info(&client, "vote_change")["numVotes"], 1i32);
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn receipts_published_after_end() {
+        let client = create_client();
+        clear_db(&client);
+
+        fn get_receipts(c: &Client) -> Value {
+            let mut req = c.get("/poll/receipts_test/receipts");
+            req.set_remote(localhost_ip!());
+            req.dispatch().into_json::<Value>().unwrap()
+        }
+        post(
+            &client,
+            "/create",
+            json!({
+                "name": "Receipts Test",
+                "description": "This poll's receipts will be published.",
+                "candidates": ["A", "B", "C"],
+                "duration": 2i32,
+                "numWinners": 1i32,
+                "id": "receipts_test",
+            }),
+        );
+        let receipt_1 = post(
+            &client,
+            "/poll/receipts_test/vote",
+            json!({ "choices": ["A", "B"] }),
+        )["receipt"]
+            .clone();
+        let receipt_2 = post(
+            &client,
+            "/poll/receipts_test/vote",
+            json!({ "choices": ["A", "B"] }),
+        )["receipt"]
+            .clone();
+        assert_ne!(receipt_1, receipt_2);
+
+        // Not published until the poll is over
+        assert_eq!(get_receipts(&client)["success"], false);
+
+        std::thread::sleep(std::time::Duration::from_secs(3));
+
+        let json = get_receipts(&client);
+        assert_eq!(json["success"], true);
+        let receipts = json["receipts"].as_array().unwrap();
+        assert_eq!(receipts.len(), 2);
+        assert!(receipts.contains(&receipt_1));
+        assert!(receipts.contains(&receipt_2));
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn events_stream() {
+        let client = create_client();
+        clear_db(&client);
+
+        let admin_token = post(
+            &client,
+            "/create",
+            json!({
+                "name": "Events Test",
+                "description": "This poll's results will be streamed.",
+                "candidates": ["A", "B", "C"],
+                "duration": 2i32,
+                "numWinners": 1i32,
+                "id": "events_test",
+                "provisionalResults": "visible_to_admin",
+            }),
+        )["adminToken"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        post(
+            &client,
+            "/poll/events_test/vote",
+            json!({ "choices": ["B", "A"] }),
+        );
+
+        // The stream lasts until the poll ends
+        let response = client
+            .get(format!("/poll/events_test/events?admin={}", admin_token))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::EventStream));
+        let body = response.into_string().unwrap();
+        assert!(body.contains(r#"{"numVotes":1}"#));
+        assert!(body.contains(r#"{"provisional":true,"winners":["B"]}"#));
+        assert!(body.contains(r#"{"numVotes":1,"winners":["B"]}"#));
+
+        // The standings are only visible to the poll's creator
+        let body = client
+            .get("/poll/events_test/events")
+            .dispatch()
+            .into_string()
+            .unwrap();
+        assert!(!body.contains("provisional"));
+        assert!(body.contains(r#""winners":["B"]"#));
+
+        let response = client.get("/poll/nonexistent/events").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    /// A stand-in for a webhook receiver. It responds to each request with the next status in
+    /// `statuses` (or 200 once they run out), and sends each request's headers and body to the channel.
+    fn webhook_receiver(statuses: Vec<u16>) -> (String, mpsc::Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut statuses = statuses.into_iter();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    headers.push_str(&line.to_lowercase());
+                }
+                let length = header(&headers, "content-length")
+                    .and_then(|length| length.parse().ok())
+                    .unwrap_or(0);
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                let status = statuses.next().unwrap_or(200);
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Stand-in\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                sender
+                    .send((headers, String::from_utf8(body).unwrap()))
+                    .ok();
+            }
+        });
+        (url, receiver)
+    }
+
+    /// Finds a header in the lowercased headers from `webhook_receiver`
+    fn header<'a>(headers: &'a str, name: &str) -> Option<&'a str> {
+        headers.lines().find_map(|line| {
+            let (header_name, value) = line.split_once(':')?;
+            (header_name == name).then(|| value.trim())
+        })
+    }
+
+    /// Runs the jobs that queue and send webhooks, rather than waiting for them
+    fn run_webhook_jobs(client: &Client) {
+        rocket::tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async {
+                let mut conn = PostgresConnection::get_one(client.rocket()).await.unwrap();
+                webhooks::queue_closed_polls(&mut conn).await.unwrap();
+                let config = client.rocket().state::<Config>().unwrap();
+                webhooks::deliver_due(&mut conn, config.allow_private_webhooks)
+                    .await
+                    .unwrap();
+            });
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn webhooks() {
+        // The stand-in receiver is on this machine, which webhooks can't be sent to by default
+        let rocket = crate::rocket();
+        let figment = rocket
+            .figment()
+            .clone()
+            .merge(("allow_private_webhooks", true));
+        let client = Client::tracked(rocket.configure(figment)).expect("valid rocket instance");
+        clear_db(&client);
+        let secret = "webhook secret for testing";
+        // The first attempt fails, so it has to be retried
+        let (url, requests) = webhook_receiver(vec![500]);
+
+        let admin_token = post(
+            &client,
+            "/create",
+            json!({
+                "name": "Webhooks Test",
+                "description": "",
+                "candidates": ["A", "B"],
+                "duration": 2i32,
+                "numWinners": 1i32,
+                "id": "webhooks_test",
+            }),
+        )["adminToken"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        // Only the poll's creator can add webhooks
+        let response = client
+            .post("/poll/webhooks_test/webhooks")
+            .json(&json!({ "url": url }))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(
+            response.into_json::<Value>().unwrap()["code"],
+            "admin_token_required"
+        );
+        for bad_webhook in [
+            json!({ "url": "ftp://example.com/" }),
+            json!({ "url": url, "secret": "too short" }),
+        ] {
+            let response = client
+                .post("/poll/webhooks_test/webhooks")
+                .header(Header::new("X-Admin-Token", admin_token.clone()))
+                .json(&bad_webhook)
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+        }
+        // Without `allow_private_webhooks`, local receivers are refused
+        let default_client = create_client();
+        let response = default_client
+            .post("/poll/webhooks_test/webhooks")
+            .header(Header::new("X-Admin-Token", admin_token.clone()))
+            .json(&json!({ "url": url }))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            response.into_json::<Value>().unwrap()["code"],
+            "invalid_webhook"
+        );
+
+        let response = client
+            .post("/poll/webhooks_test/webhooks")
+            .header(Header::new("X-Admin-Token", admin_token.clone()))
+            .json(&json!({ "url": url, "secret": secret }))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_json::<Value>().unwrap()["secret"], secret);
+
+        post(
+            &client,
+            "/poll/webhooks_test/vote",
+            json!({ "choices": ["B", "A"] }),
+        );
+        run_webhook_jobs(&client);
+        let (_, failed_body) = requests.recv().unwrap();
+
+        // Retry straight away instead of waiting for the backoff
+        connect_to_db(&client)
+            .execute(
+                "UPDATE webhook_deliveries SET next_attempt_at = NOW() WHERE delivered_at IS NULL",
+                &[],
+            )
+            .unwrap();
+        run_webhook_jobs(&client);
+        let (headers, body) = requests.recv().unwrap();
+        assert_eq!(body, failed_body);
+        assert_eq!(header(&headers, "x-betterpoll-event"), Some("vote_cast"));
+        let signature = format!("sha256={}", crypto::sign(secret, body.as_bytes()));
+        assert_eq!(
+            header(&headers, "x-betterpoll-signature"),
+            Some(signature.as_str())
+        );
+        let payload: Value = rocket::serde::json::from_str(&body).unwrap();
+        assert_eq!(payload["pollId"], "webhooks_test");
+        assert_eq!(payload["numVotes"], 1);
+
+        let log = client
+            .get("/poll/webhooks_test/webhooks")
+            .header(Header::new("X-Admin-Token", admin_token.clone()))
+            .dispatch()
+            .into_json::<Value>()
+            .unwrap();
+        assert_eq!(log["webhooks"][0]["url"], url.as_str());
+        assert_eq!(log["deliveries"][0]["status"], "delivered");
+        assert_eq!(log["deliveries"][0]["attempts"], 2);
+        assert_eq!(log["deliveries"][0]["lastStatusCode"], 200);
+
+        std::thread::sleep(std::time::Duration::from_secs(3));
+        run_webhook_jobs(&client);
+        let (headers, body) = requests.recv().unwrap();
+        assert_eq!(header(&headers, "x-betterpoll-event"), Some("poll_closed"));
+        let payload: Value = rocket::serde::json::from_str(&body).unwrap();
+        assert_eq!(payload["winners"], json!(["B"]));
+
+        // The poll is only closed once
+        run_webhook_jobs(&client);
+        assert!(requests.try_recv().is_err());
+
+        assert!(db::delete_poll(&mut connect_to_db(&client), "webhooks_test").unwrap());
+        run_webhook_jobs(&client);
+        let (headers, _) = requests.recv().unwrap();
+        assert_eq!(header(&headers, "x-betterpoll-event"), Some("poll_deleted"));
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn export_ballots() {
+        let client = create_client();
+        clear_db(&client);
+
+        let admin_token = post(
+            &client,
+            "/create",
+            json!({
+                "name": "Export Test",
+                "description": "This poll's ballots will be exported.",
+                "candidates": ["A", "B", "C"],
+                "duration": 10000i32,
+                "numWinners": 1i32,
+                "id": "export_test",
+            }),
+        )["adminToken"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        post(
+            &client,
+            "/poll/export_test/vote",
+            json!({ "choices": ["A", "B"] }),
+        );
+        post(
+            &client,
+            "/poll/export_test/vote",
+            json!({ "choices": ["C"] }),
+        );
+
+        // Only the admin can export ballots before the poll ends
+        let mut req = client.get("/poll/export_test/ballots");
+        req.set_remote(localhost_ip!());
+        let json = req.dispatch().into_json::<Value>().unwrap();
+        assert_eq!(json["success"], false);
+
+        let mut req = client
+            .get("/poll/export_test/ballots?format=json")
+            .header(Header::new("X-Admin-Token", admin_token.clone()));
         req.set_remote(localhost_ip!());
-        let json = req
-            .json(&json!({ "choices": ["A"] }))
-            .dispatch()
-            .into_json::<Value>()
-            .unwrap();
+        let json = req.dispatch().into_json::<Value>().unwrap();
         assert_eq!(json["success"], true);
+        let ballots = json["ballots"].as_array().unwrap();
+        assert_eq!(ballots.len(), 2);
+        assert!(ballots.contains(&json!(["A", "B"])));
+        assert!(ballots.contains(&json!(["C"])));
+
+        let mut req = client
+            .get("/poll/export_test/ballots?format=csv")
+            .header(Header::new("X-Admin-Token", admin_token));
+        req.set_remote(localhost_ip!());
+        let response = req.dispatch();
+        assert_eq!(response.content_type(), Some(ContentType::CSV));
+        let csv = response.into_string().unwrap();
+        assert!(csv.starts_with("Choice 1,Choice 2,Choice 3\n"));
+        assert!(csv.contains("A,B,\n"));
+        assert!(csv.contains("C,,\n"));
+    }
 
-        let mut req = client.post("/poll/vote_ip_duplicate/vote");
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn blt_import_and_export() {
+        let client = create_client();
+        clear_db(&client);
+
+        let blt = "3 1\n2 1 3 0\n1 3 0\n0\n\"Alice\"\n\"Bob\"\n\"Carol\"\n\"BLT Test\"\n";
+        let mut req = client.post("/create/blt?duration=10000&id=blt_test");
+        req.set_remote(localhost_ip!());
+        let json = req.body(blt).dispatch().into_json::<Value>().unwrap();
+        assert_eq!(json["success"], true, "no success: {:?}", json);
+        let admin_token = json["adminToken"].as_str().unwrap().to_string();
+
+        let mut req = client.get("/poll/blt_test");
+        req.set_remote(localhost_ip!());
+        let json = req.dispatch().into_json::<Value>().unwrap();
+        assert_eq!(json["name"], "BLT Test");
+        assert_eq!(json["candidates"], json!(["Alice", "Bob", "Carol"]));
+        assert_eq!(json["numWinners"], 1i32);
+        assert_eq!(json["numVotes"], 3i32);
+
+        let mut req = client
+            .get("/poll/blt_test/ballots?format=blt")
+            .header(Header::new("X-Admin-Token", admin_token));
+        req.set_remote(localhost_ip!());
+        assert_eq!(req.dispatch().into_string().unwrap(), blt);
+
+        // Invalid files are rejected
+        let mut req = client.post("/create/blt?duration=10000");
         req.set_remote(localhost_ip!());
         let json = req
-            .json(&json!({ "choices": ["A", "B", "C", "D"] }))
+            .body("3 1\n1 4 0\n0\n\"A\"\n\"B\"\n\"C\"\n\"Title\"\n")
             .dispatch()
             .into_json::<Value>()
             .unwrap();
@@ -733,15 +2046,17 @@ mod tests {
             .json(&json!({
                 "name": "A Different Name",
                 "description": "This is test poll #1.",
-                "candidates": ["A"],
+                "candidates": ["A", "B"],
                 "duration": 10000i32,
-                "numWinners": 2i32,
+                "numWinners": 1i32,
                 "id": "create_already_exists",
             }))
             .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
         let json = response.into_json::<Value>().unwrap();
         assert_eq!(json["success"], false);
         assert!(!json["error"].as_str().unwrap().is_empty());
+        assert_eq!(json["code"], "poll_id_taken");
     }
 
     #[test]
@@ -868,9 +2183,11 @@ mod tests {
                 "protection": "invalid",
             }))
             .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
         let json = response.into_json::<Value>().unwrap();
         assert_eq!(json["success"], false);
         assert!(!json["error"].as_str().unwrap().is_empty());
+        assert_eq!(json["code"], "invalid_poll");
     }
 
     #[test]
@@ -925,6 +2242,8 @@ mod tests {
         assert_eq!(response_info_ongoing_json["numVotes"], 0i32);
         assert_eq!(response_info_ongoing_json["protection"], Value::Null);
         assert_eq!(response_info_ongoing_json["ended"], false);
+        assert_eq!(response_info_ongoing_json["provisionalResults"], "hidden");
+        assert_eq!(response_info_ongoing_json["winners"], Value::Null);
 
         // ended
         post(
@@ -987,6 +2306,734 @@ mod tests {
             .map(|c| c.as_str().unwrap())
             .collect();
         assert_eq!(winners_ended, vec!["A"]);
+        assert_eq!(response_info_ended_json["provisional"], false);
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn poll_info_provisional_results() {
+        let client = create_client();
+        clear_db(&client);
+
+        fn get_info(c: &Client, id: &str, admin_token: Option<&str>) -> Value {
+            let mut req = c.get(format!("/poll/{}", id));
+            if let Some(token) = admin_token {
+                req.add_header(Header::new("X-Admin-Token", token.to_string()));
+            }
+            req.dispatch().into_json::<Value>().unwrap()
+        }
+
+        for (id, setting) in [
+            ("provisional_admin", "visible_to_admin"),
+            ("provisional_public", "public"),
+        ] {
+            let admin_token = post(
+                &client,
+                "/create",
+                json!({
+                    "name": "Provisional Results Test",
+                    "description": "",
+                    "candidates": ["A", "B", "C"],
+                    "duration": 10000i32,
+                    "numWinners": 1i32,
+                    "id": id,
+                    "provisionalResults": setting,
+                }),
+            )["adminToken"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            post(
+                &client,
+                &format!("/poll/{}/vote", id),
+                json!({ "choices": ["C", "A"] }),
+            );
+
+            let json = get_info(&client, id, Some(&admin_token));
+            assert_eq!(json["provisionalResults"], setting);
+            assert_eq!(json["ended"], false);
+            assert_eq!(json["provisional"], true);
+            assert_eq!(json["winners"], json!(["C"]));
+
+            let json = get_info(&client, id, None);
+            if setting == "public" {
+                assert_eq!(json["winners"], json!(["C"]));
+            } else {
+                assert_eq!(json["winners"], Value::Null);
+                assert_eq!(json["provisional"], Value::Null);
+            }
+        }
+
+        let mut req = client.post("/create");
+        req.set_remote(localhost_ip!());
+        let response = req
+            .json(&json!({
+                "name": "Provisional Results Test",
+                "description": "",
+                "candidates": ["A", "B"],
+                "duration": 10000i32,
+                "numWinners": 1i32,
+                "provisionalResults": "everyone",
+            }))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            response.into_json::<Value>().unwrap()["code"],
+            "invalid_poll"
+        );
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn poll_directory() {
+        let client = create_client();
+        clear_db(&client);
+
+        let polls = [
+            ("lunch", "Lunch options", "Where should we eat?", true),
+            ("board", "Board election", "Choose two board members.", true),
+            ("picnic", "Picnic food", "Sandwiches for the office", true),
+            ("secret", "Secret lunch", "Not for the directory.", false),
+        ];
+        for (id, name, description, listed) in polls {
+            post(
+                &client,
+                "/create",
+                json!({
+                    "name": name,
+                    "description": description,
+                    "candidates": ["A", "B", "C"],
+                    "duration": 1000,
+                    "numWinners": 1,
+                    "id": id,
+                    "listed": listed,
+                }),
+            );
+        }
+        let mut conn = connect_to_db(&client);
+        // Creation times are only stored to the second, so the polls are spaced out to keep
+        // their order from depending on how quickly they were created
+        for (i, (id, ..)) in polls.iter().enumerate() {
+            conn.execute(
+                "UPDATE polls SET created_at = NOW() - make_interval(mins => $2) WHERE id = $1",
+                &[id, &(polls.len() as i32 - i as i32)],
+            )
+            .unwrap();
+        }
+        conn.execute(
+            "UPDATE polls SET expires_at = NOW() - INTERVAL '1 minute' WHERE id = 'board'",
+            &[],
+        )
+        .unwrap();
+
+        let list = |path: &str| -> Value {
+            let response = client.get(path.to_string()).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let json = response.into_json::<Value>().unwrap();
+            assert_eq!(json["success"], true);
+            json
+        };
+        let ids = |json: &Value| -> Vec<String> {
+            json["polls"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|poll| poll["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let json = list("/polls");
+        assert_eq!(ids(&json), vec!["picnic", "board", "lunch"]);
+        assert_eq!(json["polls"][0]["name"], "Picnic food");
+        assert_eq!(json["polls"][0]["numVotes"], 0);
+        assert!(json["polls"][0]["endingTime"].as_u64().unwrap() > 0);
+        assert_eq!(json["nextCursor"], Value::Null);
+
+        // Pagination
+        let first = list("/polls?sort=oldest&limit=2");
+        assert_eq!(ids(&first), vec!["lunch", "board"]);
+        let cursor = first["nextCursor"].as_str().unwrap();
+        let second = list(&format!("/polls?sort=oldest&limit=2&cursor={}", cursor));
+        assert_eq!(ids(&second), vec!["picnic"]);
+        assert_eq!(second["nextCursor"], Value::Null);
+
+        // Filtering and searching
+        assert_eq!(ids(&list("/polls?status=ended")), vec!["board"]);
+        assert_eq!(
+            ids(&list("/polls?status=active&sort=ending")),
+            vec!["lunch", "picnic"]
+        );
+        assert_eq!(ids(&list("/polls?q=lunch")), vec!["lunch"]);
+        assert_eq!(ids(&list("/polls?q=office%20sandwich")), vec!["picnic"]);
+        assert!(ids(&list("/polls?q=nothing%20matches")).is_empty());
+
+        let json = list("/poll/lunch");
+        assert_eq!(json["listed"], true);
+
+        for path in [
+            "/polls?status=open",
+            "/polls?sort=votes",
+            "/polls?cursor=nonsense",
+            "/polls?limit=0",
+        ] {
+            let response = client.get(path).dispatch();
+            assert_eq!(response.status(), Status::BadRequest, "{}", path);
+            let json = response.into_json::<Value>().unwrap();
+            assert_eq!(json["code"], "invalid_query");
+        }
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn atom_feed() {
+        let client = create_client();
+        clear_db(&client);
+
+        for (id, listed) in [("open", true), ("closed", true), ("hidden", false)] {
+            post(
+                &client,
+                "/create",
+                json!({
+                    "name": format!("Fish & chips ({})", id),
+                    "description": "Which <b>shop</b>?",
+                    "candidates": ["North", "South"],
+                    "duration": 1000,
+                    "numWinners": 1,
+                    "id": id,
+                    "listed": listed,
+                }),
+            );
+        }
+        post(
+            &client,
+            "/poll/closed/vote",
+            json!({ "choices": ["South", "North"] }),
+        );
+        let mut conn = connect_to_db(&client);
+        conn.execute(
+            "UPDATE polls SET expires_at = NOW() - INTERVAL '1 minute' WHERE id = 'closed'",
+            &[],
+        )
+        .unwrap();
+
+        let response = client.get("/feed.atom").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::new("application", "atom+xml"))
+        );
+        let feed = response.into_string().unwrap();
+        assert!(feed.starts_with("<?xml"));
+        assert!(feed.contains("<title>New poll: Fish &amp; chips (open)</title>"));
+        assert!(feed.contains("<title>New poll: Fish &amp; chips (closed)</title>"));
+        assert!(feed.contains("<title>Poll closed: Fish &amp; chips (closed)</title>"));
+        assert!(feed.contains("<summary>Winner: South (1 vote)</summary>"));
+        assert!(feed.contains("<summary>Which &lt;b&gt;shop&lt;/b&gt;?</summary>"));
+        assert!(feed.contains("href=\"https://betterpoll.cc/poll/open\""));
+        assert!(!feed.contains("hidden"));
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn link_previews() {
+        let client = create_client();
+        clear_db(&client);
+
+        post(
+            &client,
+            "/create",
+            json!({
+                "name": "Best \"fish\" & chips",
+                "description": "Pick your <favourite> shop.",
+                "candidates": ["North", "South"],
+                "duration": 1000,
+                "numWinners": 1,
+                "id": "chips",
+            }),
+        );
+        post(
+            &client,
+            "/poll/chips/vote",
+            json!({ "choices": ["South", "North"] }),
+        );
+
+        let response = client.get("/p/chips").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        let page = response.into_string().unwrap();
+        assert!(page.contains(
+            "<meta property=\"og:title\" content=\"Best &quot;fish&quot; &amp; chips\">"
+        ));
+        assert!(page.contains(
+            "<meta property=\"og:description\" content=\"Voting is open, with 1 vote so far. \
+            Pick your &lt;favourite&gt; shop.\">"
+        ));
+        assert!(page.contains("<meta name=\"twitter:card\" content=\"summary\">"));
+        assert!(page.contains("content=\"0; url=https://betterpoll.cc/poll/chips\""));
+
+        let mut conn = connect_to_db(&client);
+        conn.execute(
+            "UPDATE polls SET expires_at = NOW() - INTERVAL '1 minute' WHERE id = 'chips'",
+            &[],
+        )
+        .unwrap();
+        let page = client.get("/p/chips").dispatch().into_string().unwrap();
+        assert!(page.contains("Voting has ended with 1 vote. Winner: South."));
+
+        let response = client.get("/p/nonexistent").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let page = response.into_string().unwrap();
+        assert!(page.contains("<title>Poll not found | BetterPoll</title>"));
+        assert!(page.contains("url=https://betterpoll.cc/poll/nonexistent"));
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn embeds() {
+        let client = create_client();
+        clear_db(&client);
+
+        post(
+            &client,
+            "/create",
+            json!({
+                "name": "Team <lunch>",
+                "description": "Where should we go?",
+                "candidates": ["Pizza", "Curry"],
+                "duration": 1000,
+                "numWinners": 1,
+                "id": "lunch",
+                "provisionalResults": "public",
+            }),
+        );
+        post(
+            &client,
+            "/poll/lunch/vote",
+            json!({ "choices": ["Curry", "Pizza"] }),
+        );
+
+        let response = client
+            .get("/oembed?url=https%3A%2F%2Fbetterpoll.cc%2Fpoll%2Flunch&maxwidth=300")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let json = response.into_json::<Value>().unwrap();
+        assert_eq!(json["version"], "1.0");
+        assert_eq!(json["type"], "rich");
+        assert_eq!(json["title"], "Team <lunch>");
+        assert_eq!(json["width"], 300);
+        assert_eq!(json["height"], 320);
+        let html = json["html"].as_str().unwrap();
+        assert!(html.starts_with(
+            "<iframe src=\"https://api.betterpoll.cc/v1/poll/lunch/embed\" width=\"300\""
+        ));
+        assert!(html.contains("title=\"Team &lt;lunch&gt;\""));
+
+        let response = client.get("/poll/lunch/embed").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        let csp = response
+            .headers()
+            .get_one("Content-Security-Policy")
+            .unwrap();
+        assert!(csp.contains("frame-ancestors *"));
+        let page = response.into_string().unwrap();
+        assert!(page.contains("<h1>Team &lt;lunch&gt;</h1>"));
+        assert!(page.contains("1 vote so far."));
+        assert!(page.contains("<h2>Current standings</h2>\n<ol><li>Curry</li></ol>"));
+
+        let page = client.get("/p/lunch").dispatch().into_string().unwrap();
+        assert!(page.contains(
+            "href=\"https://api.betterpoll.cc/v1/oembed?url=https%3A%2F%2Fbetterpoll.cc%2Fpoll%2Flunch&amp;format=json\""
+        ));
+
+        for (path, status, code) in [
+            (
+                "/oembed?url=https%3A%2F%2Fexample.com%2Fpoll%2Flunch",
+                Status::NotFound,
+                "unknown_embed_url",
+            ),
+            (
+                "/oembed?url=https%3A%2F%2Fbetterpoll.cc%2Fpoll%2Fnonexistent",
+                Status::NotFound,
+                "unknown_embed_url",
+            ),
+            (
+                "/oembed?url=https%3A%2F%2Fbetterpoll.cc%2Fpoll%2Flunch&format=xml",
+                Status::NotImplemented,
+                "embed_format_not_supported",
+            ),
+            (
+                "/poll/nonexistent/embed",
+                Status::NotFound,
+                "poll_not_found",
+            ),
+        ] {
+            let response = client.get(path).dispatch();
+            assert_eq!(response.status(), status, "{}", path);
+            let json = response.into_json::<Value>().unwrap();
+            assert_eq!(json["code"], code);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn calendar_export() {
+        let client = create_client();
+        clear_db(&client);
+
+        post(
+            &client,
+            "/create",
+            json!({
+                "name": "Lunch",
+                "description": "Where should we go?",
+                "candidates": ["Pizza", "Curry"],
+                "duration": 3600,
+                "numWinners": 1,
+                "id": "lunch.v2",
+            }),
+        );
+
+        let response = client.get("/poll/lunch.v2.ics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::Calendar));
+        let calendar = response.into_string().unwrap();
+        assert!(calendar.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(calendar.contains("\r\nUID:poll-lunch.v2@betterpoll.cc\r\n"));
+        assert!(calendar.contains("\r\nSUMMARY:Poll: Lunch\r\n"));
+        assert!(calendar.contains("\r\nURL:https://betterpoll.cc/poll/lunch.v2\r\n"));
+        assert!(calendar.contains("\r\nBEGIN:VALARM\r\n"));
+
+        // Other poll routes aren't affected
+        let json = client.get("/poll/lunch.v2").dispatch().into_json::<Value>();
+        assert_eq!(json.unwrap()["name"], "Lunch");
+
+        let response = client.get("/poll/nonexistent.ics").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let json = response.into_json::<Value>().unwrap();
+        assert_eq!(json["code"], "poll_not_found");
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn qr_codes() {
+        let client = create_client();
+        clear_db(&client);
+
+        post(
+            &client,
+            "/create",
+            json!({
+                "name": "Lunch",
+                "description": "",
+                "candidates": ["Pizza", "Curry"],
+                "duration": 3600,
+                "numWinners": 1,
+                "id": "lunch",
+            }),
+        );
+
+        let response = client.get("/poll/lunch/qr.svg").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::SVG));
+        assert!(response.into_string().unwrap().contains("<svg"));
+
+        let response = client.get("/poll/lunch/qr.png?size=512").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::PNG));
+        let png = response.into_bytes().unwrap();
+        let image = image::load_from_memory(&png).unwrap().to_luma8();
+        assert!(image.width() >= 512);
+
+        for (path, status, code) in [
+            (
+                "/poll/nonexistent/qr.svg",
+                Status::NotFound,
+                "poll_not_found",
+            ),
+            (
+                "/poll/lunch/qr.png?size=10",
+                Status::BadRequest,
+                "invalid_query",
+            ),
+            (
+                "/poll/lunch/qr.svg?size=5000",
+                Status::BadRequest,
+                "invalid_query",
+            ),
+        ] {
+            let response = client.get(path).dispatch();
+            assert_eq!(response.status(), status, "{}", path);
+            let json = response.into_json::<Value>().unwrap();
+            assert_eq!(json["code"], code);
+        }
+    }
+
+    /// A stand-in for an SMTP relay, like MailHog. It accepts every email, and sends each one's
+    /// recipient and data to the channel.
+    fn smtp_relay() -> (u16, mpsc::Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                write!(stream, "220 stand-in ESMTP\r\n").unwrap();
+                let mut recipient = String::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 {
+                        break;
+                    }
+                    let command = line.trim_end().to_uppercase();
+                    if command.starts_with("RCPT TO:") {
+                        recipient = line.trim_end()[8..]
+                            .trim_matches(|c| c == '<' || c == '>')
+                            .to_string();
+                    }
+                    if command == "DATA" {
+                        write!(stream, "354 go ahead\r\n").unwrap();
+                        let mut data = String::new();
+                        loop {
+                            let mut line = String::new();
+                            reader.read_line(&mut line).unwrap();
+                            if line == ".\r\n" {
+                                break;
+                            }
+                            data.push_str(&line);
+                        }
+                        write!(stream, "250 queued\r\n").unwrap();
+                        sender.send((recipient.clone(), data)).ok();
+                    } else if command == "QUIT" {
+                        write!(stream, "221 bye\r\n").unwrap();
+                        break;
+                    } else {
+                        write!(stream, "250 ok\r\n").unwrap();
+                    }
+                }
+            }
+        });
+        (port, receiver)
+    }
+
+    /// Runs the jobs that queue and send emails, rather than waiting for them
+    fn run_email_jobs(client: &Client) {
+        let config = client.rocket().state::<Config>().unwrap();
+        let smtp = config.smtp.as_ref().unwrap();
+        let from = smtp.from.parse().unwrap();
+        rocket::tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async {
+                let mailer = email::mailer(smtp).unwrap();
+                let mut conn = PostgresConnection::get_one(client.rocket()).await.unwrap();
+                email::queue_closed_polls(&mut conn, config).await.unwrap();
+                email::deliver_due(&mut conn, &mailer, &from).await.unwrap();
+            });
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn invitations_and_emails() {
+        let create_request = json!({
+            "name": "Invitations Test",
+            "description": "",
+            "candidates": ["A", "B"],
+            "duration": 2i32,
+            "numWinners": 1i32,
+            "id": "invitations_test",
+            "protection": "invite",
+            "invitees": ["one@example.com", "two@example.com", "one@example.com"],
+            "creatorEmail": "creator@example.com",
+        });
+
+        // Without an SMTP relay, polls can't be invitation-only
+        let client = create_client();
+        clear_db(&client);
+        let response = client.post("/create").json(&create_request).dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            response.into_json::<Value>().unwrap()["code"],
+            "invalid_poll"
+        );
+
+        let (port, emails) = smtp_relay();
+        let rocket = crate::rocket();
+        let figment = rocket
+            .figment()
+            .clone()
+            .merge(("smtp.host", "127.0.0.1"))
+            .merge(("smtp.port", port))
+            .merge(("smtp.security", "none"))
+            .merge(("smtp.from", "BetterPoll <polls@example.com>"));
+        let client = Client::tracked(rocket.configure(figment)).expect("valid rocket instance");
+
+        for (key, value) in [
+            ("invitees", json!([])),
+            ("invitees", json!(["not an address"])),
+            ("protection", json!("ip")),
+            ("creatorEmail", json!("not an address")),
+        ] {
+            let mut request = create_request.clone();
+            request[key] = value;
+            let response = client.post("/create").json(&request).dispatch();
+            assert_eq!(response.status(), Status::BadRequest, "{:?}", request);
+        }
+
+        post(&client, "/create", create_request);
+        let info = client
+            .get("/poll/invitations_test")
+            .dispatch()
+            .into_json::<Value>()
+            .unwrap();
+        assert_eq!(info["protection"], "invite");
+
+        // Each invitee gets one email, with their own voting link
+        run_email_jobs(&client);
+        let mut invitations = [emails.recv().unwrap(), emails.recv().unwrap()];
+        invitations.sort();
+        assert_eq!(invitations[0].0, "one@example.com");
+        assert_eq!(invitations[1].0, "two@example.com");
+        let link = "https://betterpoll.cc/poll/invitations_test?invite=";
+        let tokens: Vec<String> = invitations
+            .iter()
+            .map(|(_, data)| {
+                assert!(data.contains("Subject: You're invited to vote in \"Invitations Test\""));
+                // The body is quoted-printable, which escapes `=` and wraps long lines
+                let data = data.replace("=\r\n", "").replace("=3D", "=");
+                let start = data.find(link).unwrap() + link.len();
+                data[start..start + 32].to_string()
+            })
+            .collect();
+        assert_ne!(tokens[0], tokens[1]);
+        assert!(emails.try_recv().is_err());
+
+        // Sent emails aren't kept, since they contain voting links
+        let bodies = connect_to_db(&client)
+            .query("SELECT body FROM emails WHERE sent_at IS NOT NULL", &[])
+            .unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert!(bodies
+            .iter()
+            .all(|row| row.get::<_, String>("body").is_empty()));
+
+        // Votes need an invitation, and each invitation can only be used once
+        for invite in [None, Some("wrong")] {
+            let mut req = client.post("/poll/invitations_test/vote");
+            req.set_remote(localhost_ip!());
+            let response = req
+                .json(&json!({ "choices": ["A"], "invite": invite }))
+                .dispatch();
+            assert_eq!(response.status(), Status::Forbidden);
+            assert_eq!(
+                response.into_json::<Value>().unwrap()["code"],
+                "invalid_invitation"
+            );
+        }
+        post(
+            &client,
+            "/poll/invitations_test/vote",
+            json!({ "choices": ["B", "A"], "invite": tokens[0] }),
+        );
+        let mut req = client.post("/poll/invitations_test/vote");
+        req.set_remote(localhost_ip!());
+        let response = req
+            .json(&json!({ "choices": ["A"], "invite": tokens[0] }))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        // The same client can vote again with another invitation
+        post(
+            &client,
+            "/poll/invitations_test/vote",
+            json!({ "choices": ["B"], "invite": tokens[1] }),
+        );
+
+        // The creator is emailed the results once the poll ends
+        std::thread::sleep(std::time::Duration::from_secs(3));
+        run_email_jobs(&client);
+        let (recipient, data) = emails.recv().unwrap();
+        assert_eq!(recipient, "creator@example.com");
+        assert!(data.contains("Subject: Results of \"Invitations Test\""));
+        assert!(data.contains("2 people voted."));
+        assert!(data.contains("Winner:\r\n1. B"));
+        run_email_jobs(&client);
+        assert!(emails.try_recv().is_err());
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn status_and_health() {
+        let client = create_client();
+        clear_db(&client);
+
+        let response = client.get("/status").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let json = response.into_json::<Value>().unwrap();
+        assert_eq!(json["success"], true);
+        assert_eq!(json["total"], 0);
+
+        let response = client.get("/health/live").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let json = response.into_json::<Value>().unwrap();
+        assert_eq!(json["success"], true);
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+
+        let response = client.get("/health/ready").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let json = response.into_json::<Value>().unwrap();
+        assert_eq!(json["success"], true);
+        assert_eq!(json["database"], "ok");
+        assert_eq!(
+            json["schemaVersion"],
+            crate::database::postgres::SCHEMA_VERSION
+        );
+    }
+
+    #[test]
+    #[cfg_attr(feature = "no-db-test", ignore)]
+    #[serial]
+    fn metrics_exported() {
+        let client = create_client();
+        clear_db(&client);
+
+        post(
+            &client,
+            "/create",
+            json!({
+                "name": "Metrics Test",
+                "description": "creating this poll should be counted",
+                "candidates": ["A", "B"],
+                "duration": 10000i32,
+                "numWinners": 1i32,
+                "id": "metrics_test",
+            }),
+        );
+        post(
+            &client,
+            "/poll/metrics_test/vote",
+            json!({ "choices": ["A"] }),
+        );
+
+        let response = client.get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let metrics = response.into_string().unwrap();
+        for metric in [
+            "betterpoll_polls_created_total",
+            "betterpoll_votes_cast_total",
+            "betterpoll_db_pool_wait_seconds",
+            "betterpoll_http_requests_total{method=\"POST\",route=\"create\",status=\"200\"}",
+        ] {
+            assert!(metrics.contains(metric), "{} is missing", metric);
+        }
     }
 
     #[test]
@@ -999,9 +3046,16 @@ mod tests {
         let mut req = client.get("/poll/nonexistent");
         req.set_remote(localhost_ip!());
         let response_nonexistent = req.dispatch();
-        assert_eq!(response_nonexistent.status(), Status::Ok);
+        assert_eq!(response_nonexistent.status(), Status::NotFound);
+        let request_id = response_nonexistent
+            .headers()
+            .get_one("X-Request-Id")
+            .unwrap()
+            .to_string();
         let response_nonexistent_json = response_nonexistent.into_json::<Value>().unwrap();
         assert_eq!(response_nonexistent_json["success"], false);
         assert_eq!(response_nonexistent_json["name"], json!(null));
+        assert_eq!(response_nonexistent_json["code"], "poll_not_found");
+        assert_eq!(response_nonexistent_json["requestId"], request_id);
     }
 }
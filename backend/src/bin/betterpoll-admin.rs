@@ -0,0 +1,299 @@
+//! Command-line tool for administering a BetterPoll database.
+//!
+//! The database URL and server secret are read from `Rocket.toml` and `ROCKET_` environment
+//! variables, the same way the server reads them.
+
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+use std::time::SystemTime;
+
+use betterpoll::config::Config;
+use betterpoll::database::{migrations, postgres as db};
+use betterpoll::error::ErrorKind;
+use betterpoll::poll::Poll;
+use betterpoll::{blt, export};
+use clap::{ArgEnum, Parser, Subcommand};
+use postgres::{Client, NoTls};
+use rocket::serde::json::json;
+
+#[derive(Parser)]
+#[clap(about = "Administers a BetterPoll database")]
+struct Args {
+    /// Postgres URL to connect to, instead of the production database from Rocket.toml
+    #[clap(long)]
+    database_url: Option<String>,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Lists the most recently created polls
+    List {
+        /// Only list polls whose ID or title contains this
+        #[clap(long)]
+        search: Option<String>,
+        #[clap(long, default_value = "50")]
+        limit: i64,
+    },
+    /// Shows a poll's details
+    Show { id: String },
+    /// Tallies a poll's ballots, even if it hasn't ended yet
+    Tally { id: String },
+    /// Deletes a poll and all of its ballots
+    Delete {
+        id: String,
+        /// Don't ask for confirmation
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Exports a poll's ballots
+    Export {
+        id: String,
+        #[clap(long, arg_enum, default_value = "json")]
+        format: Format,
+        /// File to write to, instead of stdout
+        #[clap(long)]
+        output: Option<String>,
+    },
+    /// Creates the schema or upgrades it to the latest version
+    Migrate,
+    /// Recomputes the winners of a poll, or of every poll that has ended
+    Retally { id: Option<String> },
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+enum Format {
+    Json,
+    Csv,
+    Blt,
+}
+
+fn main() {
+    let args = Args::parse();
+    let figment = rocket::Config::figment();
+
+    let url = match args.database_url {
+        Some(url) => url,
+        None => match figment.extract_inner::<String>("databases.production_db.url") {
+            Ok(url) => url,
+            Err(e) => fail(format!(
+                "Couldn't find the database URL in Rocket.toml: {}",
+                e
+            )),
+        },
+    };
+    let mut client = match Client::connect(&url, NoTls) {
+        Ok(client) => client,
+        Err(e) => fail(format!("Couldn't connect to the database: {}", e)),
+    };
+
+    let result = match args.command {
+        Command::List { search, limit } => list(&mut client, search.as_deref(), limit),
+        Command::Show { id } => show(&mut client, &id),
+        Command::Tally { id } => tally(&mut client, &id),
+        Command::Delete { id, yes } => delete(&mut client, &id, yes),
+        Command::Export { id, format, output } => export(&mut client, &id, format, output),
+        Command::Migrate => match figment.extract::<Config>() {
+            Ok(config) => match config.check_secret() {
+                Ok(()) => migrate(&mut client, &config),
+                Err(message) => Err(format!(
+                    "{} Data is migrated with the same secret as the server uses.",
+                    message
+                )),
+            },
+            Err(e) => Err(format!(
+                "Couldn't read the configuration in Rocket.toml: {}",
+                e
+            )),
+        },
+        Command::Retally { id } => retally(&mut client, id.as_deref()),
+    };
+    if let Err(message) = result {
+        fail(message);
+    }
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
+}
+
+/// Formats an error for the terminal; unlike the API, internal errors are shown in full
+fn describe(error: ErrorKind) -> String {
+    match error {
+        ErrorKind::Visible(e) => e.to_string(),
+        ErrorKind::Internal(e) => format!("{:?}", e),
+    }
+}
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn find_poll(client: &mut Client, id: &str) -> Result<Poll, String> {
+    match db::get_poll(client, id).map_err(describe)? {
+        Some(poll) => Ok(poll),
+        None => Err(format!("There is no poll with the ID '{}'.", id)),
+    }
+}
+
+fn list(client: &mut Client, search: Option<&str>, limit: i64) -> Result<(), String> {
+    let polls = db::list_polls(client, search, limit).map_err(describe)?;
+    let now = SystemTime::now();
+    println!(
+        "{:<24} {:>7} {:>12} {:>12}  TITLE",
+        "ID", "VOTES", "CREATED", "ENDS"
+    );
+    for poll in polls {
+        println!(
+            "{:<24} {:>7} {:>12} {:>12}{} {}",
+            poll.id,
+            poll.num_votes,
+            seconds_since_epoch(poll.created_at),
+            seconds_since_epoch(poll.expires_at),
+            if poll.expires_at <= now { "*" } else { " " },
+            poll.title,
+        );
+    }
+    println!("Times are in seconds since the UNIX epoch; * marks polls that have ended.");
+    Ok(())
+}
+
+fn show(client: &mut Client, id: &str) -> Result<(), String> {
+    let poll = find_poll(client, id)?;
+    println!("ID:          {}", poll.id);
+    println!("Title:       {}", poll.title);
+    println!("Description: {}", poll.description);
+    println!("Candidates:  {}", poll.candidates.join(", "));
+    println!("Method:      {}", poll.method.name());
+    println!("Winners:     {}", poll.num_winners);
+    println!("Created:     {}", poll.creation_time);
+    println!(
+        "Ends:        {}{}",
+        poll.end_time,
+        if poll.has_ended() { " (ended)" } else { "" }
+    );
+    println!("IP limited:  {}", poll.prohibit_double_vote_by_ip);
+    println!("Ballots:     {}", poll.votes.len());
+    println!("Admin token: {}", poll.admin_token);
+    Ok(())
+}
+
+fn tally(client: &mut Client, id: &str) -> Result<(), String> {
+    let poll = find_poll(client, id)?;
+    let winners = poll.find_winners().map_err(describe)?;
+    println!("{} ballots", poll.votes.len());
+    for winner in winners {
+        println!("{}. {}", winner.rank + 1, winner.candidate);
+    }
+    Ok(())
+}
+
+fn delete(client: &mut Client, id: &str, yes: bool) -> Result<(), String> {
+    let poll = find_poll(client, id)?;
+    if !yes {
+        print!(
+            "Delete '{}' and its {} ballots? Type the poll ID to confirm: ",
+            poll.title,
+            poll.votes.len()
+        );
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut answer = String::new();
+        io::stdin()
+            .read_line(&mut answer)
+            .map_err(|e| e.to_string())?;
+        if answer.trim() != id {
+            return Err("Not deleting the poll.".to_string());
+        }
+    }
+
+    if db::delete_poll(client, id).map_err(describe)? {
+        println!("Deleted poll '{}'.", id);
+    }
+    Ok(())
+}
+
+fn export(
+    client: &mut Client,
+    id: &str,
+    format: Format,
+    output: Option<String>,
+) -> Result<(), String> {
+    let poll = find_poll(client, id)?;
+    let ballots = poll.anonymised_ballots();
+    let exported = match format {
+        Format::Json => json!({
+            "candidates": poll.candidates,
+            "ballots": ballots,
+        })
+        .to_string(),
+        Format::Csv => export::ballots_to_csv(&poll, &ballots).map_err(describe)?,
+        Format::Blt => blt::write(&poll),
+    };
+
+    match output {
+        Some(path) => fs::write(&path, exported).map_err(|e| format!("{}: {}", path, e)),
+        None => {
+            print!("{}", exported);
+            Ok(())
+        }
+    }
+}
+
+fn migrate(client: &mut Client, config: &Config) -> Result<(), String> {
+    let applied = migrations::migrate(client, config).map_err(describe)?;
+    if applied.is_empty() {
+        println!("The database is already at version {}.", db::SCHEMA_VERSION);
+    } else {
+        for version in applied {
+            println!("Migrated to version {}.", version);
+        }
+    }
+    Ok(())
+}
+
+/// Winners aren't stored in the database; they're tallied whenever an ended poll is loaded.
+/// This checks that every ended poll can still be tallied, for example after a migration.
+fn retally(client: &mut Client, id: Option<&str>) -> Result<(), String> {
+    let ids = match id {
+        Some(id) => vec![id.to_string()],
+        None => {
+            let now = SystemTime::now();
+            db::list_polls(client, None, i64::MAX)
+                .map_err(describe)?
+                .into_iter()
+                .filter(|poll| poll.expires_at <= now)
+                .map(|poll| poll.id)
+                .collect()
+        }
+    };
+
+    let mut failures = 0;
+    for id in &ids {
+        match find_poll(client, id).and_then(|poll| poll.find_winners().map_err(describe)) {
+            Ok(winners) => {
+                let names: Vec<&str> = winners.iter().map(|w| w.candidate.as_str()).collect();
+                println!("{}: {}", id, names.join(", "));
+            }
+            Err(message) => {
+                failures += 1;
+                eprintln!("{}: {}", id, message);
+            }
+        }
+    }
+
+    if failures > 0 {
+        Err(format!(
+            "{} of {} polls couldn't be tallied.",
+            failures,
+            ids.len()
+        ))
+    } else {
+        Ok(())
+    }
+}
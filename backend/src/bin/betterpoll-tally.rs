@@ -0,0 +1,262 @@
+//! Counts ballots from a file with the same code as the server, for example to check a poll's
+//! result or to count a paper election.
+//!
+//! Ballots can be read from the JSON, CSV and BLT formats that polls are exported in.
+
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+use betterpoll::error::ErrorKind;
+use betterpoll::poll::{Poll, RankedChoiceVote, VotingMethod};
+use betterpoll::{blt, export};
+use clap::{ArgEnum, Parser};
+use rocket::serde::Deserialize;
+
+#[derive(Parser)]
+#[clap(about = "Counts ballots from a JSON, CSV or BLT file")]
+struct Args {
+    /// File to read ballots from
+    file: String,
+
+    /// Format of the file; guessed from its extension if not given
+    #[clap(long, arg_enum)]
+    format: Option<Format>,
+
+    #[clap(long, arg_enum, default_value = "schulze")]
+    method: Method,
+
+    /// Number of winners; defaults to the number of seats in a BLT file, or 1
+    #[clap(long)]
+    winners: Option<usize>,
+
+    /// A candidate in the election. If none are given, the candidates are taken from the file,
+    /// or from the ballots if the file doesn't list them.
+    #[clap(long = "candidate")]
+    candidates: Vec<String>,
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+enum Format {
+    Json,
+    Csv,
+    Blt,
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+enum Method {
+    Schulze,
+}
+
+/// Ballots as exported by `GET /poll/<id>/ballots?format=json`
+#[derive(Deserialize)]
+struct JsonBallots {
+    candidates: Option<Vec<String>>,
+    ballots: Vec<Vec<String>>,
+}
+
+/// An election read from a file
+struct Election {
+    title: String,
+    candidates: Vec<String>,
+    num_seats: Option<usize>,
+    ballots: Vec<Vec<String>>,
+}
+
+fn main() {
+    if let Err(message) = run(Args::parse()) {
+        eprintln!("error: {}", message);
+        process::exit(1);
+    }
+}
+
+/// Formats an error for the terminal
+fn describe(error: ErrorKind) -> String {
+    match error {
+        ErrorKind::Visible(e) => e.to_string(),
+        ErrorKind::Internal(e) => format!("{:?}", e),
+    }
+}
+
+fn read_election(path: &str, format: Format) -> Result<Election, String> {
+    let input = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let title = Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    match format {
+        Format::Json => {
+            let data: JsonBallots =
+                rocket::serde::json::from_str(&input).map_err(|e| e.to_string())?;
+            Ok(Election {
+                title,
+                candidates: data.candidates.unwrap_or_default(),
+                num_seats: None,
+                ballots: data.ballots,
+            })
+        }
+        Format::Csv => Ok(Election {
+            title,
+            candidates: vec![],
+            num_seats: None,
+            ballots: export::ballots_from_csv(&input)?,
+        }),
+        Format::Blt => {
+            let election = blt::parse(&input)?;
+            Ok(Election {
+                title: election.title,
+                candidates: election.candidates,
+                num_seats: Some(election.num_seats),
+                ballots: election.ballots,
+            })
+        }
+    }
+}
+
+/// Lists every candidate who appears on a ballot, in the order they first appear
+fn candidates_from_ballots(ballots: &[Vec<String>]) -> Vec<String> {
+    let mut candidates: Vec<String> = vec![];
+    for choice in ballots.iter().flatten() {
+        if !candidates.contains(choice) {
+            candidates.push(choice.clone());
+        }
+    }
+    candidates
+}
+
+/// Checks that each ballot only ranks candidates, and ranks each of them at most once
+fn validate_ballots(candidates: &[String], ballots: &[Vec<String>]) -> Result<(), String> {
+    for (number, ballot) in ballots.iter().enumerate() {
+        for (position, choice) in ballot.iter().enumerate() {
+            if !candidates.contains(choice) {
+                return Err(format!(
+                    "Ballot {} ranks '{}', who isn't a candidate.",
+                    number + 1,
+                    choice
+                ));
+            }
+            if ballot[..position].contains(choice) {
+                return Err(format!(
+                    "Ballot {} ranks '{}' more than once.",
+                    number + 1,
+                    choice
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let format = match args.format {
+        Some(format) => format,
+        None => match Path::new(&args.file)
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some("json") => Format::Json,
+            Some("csv") => Format::Csv,
+            Some("blt") => Format::Blt,
+            _ => return Err("Couldn't tell the file's format; use --format.".to_string()),
+        },
+    };
+    let election = read_election(&args.file, format)?;
+
+    let candidates = if !args.candidates.is_empty() {
+        args.candidates
+    } else if !election.candidates.is_empty() {
+        election.candidates
+    } else {
+        candidates_from_ballots(&election.ballots)
+    };
+    validate_ballots(&candidates, &election.ballots)?;
+
+    let num_winners = args.winners.or(election.num_seats).unwrap_or(1);
+    if num_winners == 0 || num_winners > candidates.len() {
+        return Err(format!(
+            "There must be between 1 and {} winners.",
+            candidates.len()
+        ));
+    }
+
+    let mut poll = Poll::new(
+        None,
+        election.title,
+        String::new(),
+        candidates,
+        Duration::ZERO,
+        num_winners,
+        false,
+    )
+    .map_err(describe)?;
+    poll.method = match args.method {
+        Method::Schulze => VotingMethod::Schulze,
+    };
+    poll.votes = election
+        .ballots
+        .into_iter()
+        .map(|ranked_choices| RankedChoiceVote {
+            ranked_choices,
+            voter_ip_hash: None,
+        })
+        .collect();
+
+    let winners = poll.find_winners().map_err(describe)?;
+    let ranking = poll.full_ranking().map_err(describe)?;
+
+    if !poll.title.is_empty() {
+        println!("{}", poll.title);
+    }
+    println!(
+        "{} ballots, {} candidates, {} method",
+        poll.votes.len(),
+        poll.candidates.len(),
+        poll.method.name()
+    );
+
+    println!("\nWinners:");
+    for winner in &winners {
+        println!("  {}. {}", winner.rank + 1, winner.candidate);
+    }
+
+    println!("\nRanking:");
+    for candidate in &ranking {
+        println!("  {}. {}", candidate.rank + 1, candidate.candidate);
+    }
+
+    match poll.method {
+        VotingMethod::Schulze => {
+            println!("\nPairwise preferences (row candidate over column candidate):");
+            print_matrix(&poll.candidates, &poll.pairwise_preferences());
+            println!("\nStrongest path strengths (winning votes):");
+            print_matrix(&poll.candidates, &poll.schulze_path_strengths());
+        }
+    }
+    Ok(())
+}
+
+fn print_matrix(candidates: &[String], matrix: &[Vec<u64>]) {
+    let width = candidates
+        .iter()
+        .map(|candidate| candidate.chars().count())
+        .max()
+        .unwrap_or(0);
+    print!("  {:width$}", "", width = width);
+    for number in 1..=candidates.len() {
+        print!(" {:>6}", number);
+    }
+    println!();
+    for (number, (candidate, row)) in candidates.iter().zip(matrix).enumerate() {
+        print!("  {:width$}", candidate, width = width);
+        for (column, count) in row.iter().enumerate() {
+            if column == number {
+                print!(" {:>6}", "-");
+            } else {
+                print!(" {:>6}", count);
+            }
+        }
+        println!("  ({})", number + 1);
+    }
+}
@@ -0,0 +1,351 @@
+//! Reading and writing the BLT election file format.
+//!
+//! BLT is used by most STV counting programs (OpenSTV, Droop, etc.). A file looks like this:
+//! ```text
+//! 3 1
+//! 2 1 3 0
+//! 1 3 0
+//! 0
+//! "Alice"
+//! "Bob"
+//! "Carol"
+//! "Title of the election"
+//! ```
+//! The first line gives the number of candidates and seats. Each ballot line has a weight,
+//! then the candidates' numbers in order of preference, then a `0`. A lone `0` ends the ballots,
+//! and is followed by the candidates' names and the title.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::poll::{Poll, MAX_CANDIDATES};
+
+/// An election read from a BLT file
+#[derive(Debug, PartialEq)]
+pub struct BltElection {
+    pub title: String,
+    pub candidates: Vec<String>,
+    pub num_seats: usize,
+    /// Ranked choices, with identical ballots expanded so that there is one entry per voter
+    pub ballots: Vec<Vec<String>>,
+}
+
+/// The most ballots that a BLT file can contain, after expanding weighted lines
+pub const MAX_BALLOTS: u64 = 100_000;
+
+/// Formats a poll as BLT, collapsing identical ballots into weighted lines
+pub fn write(poll: &Poll) -> String {
+    let mut weights: HashMap<&[String], u64> = HashMap::new();
+    for vote in &poll.votes {
+        *weights.entry(vote.ranked_choices.as_slice()).or_insert(0) += 1;
+    }
+    // Heaviest ballots first; ties are broken so that the output is deterministic
+    let mut weighted_ballots: Vec<(&[String], u64)> = weights.into_iter().collect();
+    weighted_ballots.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+    let mut blt = format!("{} {}\n", poll.candidates.len(), poll.num_winners);
+    for (ballot, weight) in weighted_ballots {
+        write!(blt, "{}", weight).unwrap();
+        for choice in ballot {
+            // Ballots are validated when they're cast, so every choice is a candidate
+            if let Some(index) = poll.candidates.iter().position(|c| c == choice) {
+                write!(blt, " {}", index + 1).unwrap();
+            }
+        }
+        blt.push_str(" 0\n");
+    }
+    blt.push_str("0\n");
+
+    for candidate in &poll.candidates {
+        writeln!(blt, "\"{}\"", candidate.replace('"', "'")).unwrap();
+    }
+    writeln!(blt, "\"{}\"", poll.title.replace('"', "'")).unwrap();
+
+    blt
+}
+
+/// Splits BLT into tokens. Quoted strings are a single token (without the quotes), and
+/// everything after a `#` on a line is a comment.
+fn tokenize(input: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    for line in input.lines() {
+        let mut chars = line.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if c == '#' {
+                break;
+            } else if c == '"' {
+                chars.next();
+                let mut token = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => token.push(c),
+                        None => return Err(format!("Unterminated string in the line '{}'.", line)),
+                    }
+                }
+                tokens.push(token);
+            } else {
+                let mut token = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '"' || c == '#' {
+                        break;
+                    }
+                    token.push(c);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number<T: std::str::FromStr>(token: Option<String>, what: &str) -> Result<T, String> {
+    match token {
+        Some(token) => token
+            .parse()
+            .map_err(|_| format!("Expected {}, but found '{}'.", what, token)),
+        None => Err(format!("Expected {}, but the file ended.", what)),
+    }
+}
+
+/// Parses a BLT file.
+///
+/// Withdrawn candidates are removed from the election, and ballots left empty by that are dropped.
+/// Equal rankings (`1=2`) aren't supported.
+pub fn parse(input: &str) -> Result<BltElection, String> {
+    let mut tokens = tokenize(input)?.into_iter().peekable();
+
+    let num_candidates: usize = parse_number(tokens.next(), "the number of candidates")?;
+    let num_seats: usize = parse_number(tokens.next(), "the number of seats")?;
+    // Checked before anything is allocated for the candidates
+    if num_candidates > MAX_CANDIDATES {
+        return Err(format!(
+            "A BLT file can't contain more than {} candidates.",
+            MAX_CANDIDATES
+        ));
+    }
+    if num_seats == 0 || num_seats > num_candidates {
+        return Err(format!(
+            "The number of seats must be between 1 and the number of candidates ({}).",
+            num_candidates
+        ));
+    }
+
+    let mut withdrawn = vec![];
+    while let Some(token) = tokens.peek() {
+        if !token.starts_with('-') {
+            break;
+        }
+        let index: i64 = parse_number(tokens.next(), "a withdrawn candidate")?;
+        match index
+            .checked_neg()
+            .and_then(|index| usize::try_from(index).ok())
+        {
+            Some(index) if (1..=num_candidates).contains(&index) => withdrawn.push(index),
+            _ => {
+                return Err(format!(
+                    "There is no candidate number {} to withdraw; there are only {} candidates.",
+                    index.unsigned_abs(),
+                    num_candidates
+                ))
+            }
+        }
+    }
+
+    let mut weighted_ballots: Vec<(u64, Vec<usize>)> = vec![];
+    let mut total_ballots = 0u64;
+    loop {
+        // Some programs give each ballot an ID in parentheses before its weight
+        if matches!(tokens.peek(), Some(token) if token.starts_with('(')) {
+            tokens.next();
+        }
+
+        let weight: u64 = parse_number(tokens.next(), "a ballot weight")?;
+        if weight == 0 {
+            break;
+        }
+        total_ballots += weight;
+        if total_ballots > MAX_BALLOTS {
+            return Err(format!(
+                "A BLT file can't contain more than {} ballots.",
+                MAX_BALLOTS
+            ));
+        }
+
+        let mut ballot = vec![];
+        loop {
+            let token = tokens.next();
+            if token.as_deref() == Some("-") {
+                // Skipped preference
+                continue;
+            }
+            if matches!(&token, Some(token) if token.contains('=')) {
+                return Err("Ballots with equal rankings are not supported.".to_string());
+            }
+            let index: usize = parse_number(token, "a candidate number")?;
+            if index == 0 {
+                break;
+            }
+            if index > num_candidates {
+                return Err(format!(
+                    "There is no candidate number {}; there are only {} candidates.",
+                    index, num_candidates
+                ));
+            }
+            if ballot.contains(&index) {
+                return Err(format!(
+                    "A ballot ranks candidate number {} more than once.",
+                    index
+                ));
+            }
+            ballot.push(index);
+        }
+        weighted_ballots.push((weight, ballot));
+    }
+
+    let mut names = Vec::with_capacity(num_candidates);
+    for n in 1..=num_candidates {
+        match tokens.next() {
+            Some(name) => names.push(name),
+            None => return Err(format!("The name of candidate number {} is missing.", n)),
+        }
+    }
+    let title = match tokens.next() {
+        Some(title) => title,
+        None => return Err("The title of the election is missing.".to_string()),
+    };
+
+    let name_of = |index: &usize| names[index - 1].clone();
+    let mut ballots = vec![];
+    for (weight, ballot) in weighted_ballots {
+        let ballot: Vec<String> = ballot
+            .iter()
+            .filter(|index| !withdrawn.contains(*index))
+            .map(name_of)
+            .collect();
+        if ballot.is_empty() {
+            continue;
+        }
+        for _ in 0..weight {
+            ballots.push(ballot.clone());
+        }
+    }
+    let candidates = (1..=num_candidates)
+        .filter(|index| !withdrawn.contains(index))
+        .map(|index| name_of(&index))
+        .collect();
+
+    Ok(BltElection {
+        title,
+        candidates,
+        num_seats,
+        ballots,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::poll::RankedChoiceVote;
+
+    fn strings(strs: &[&str]) -> Vec<String> {
+        strs.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_blt() {
+        let election = parse(
+            "3 1
+            # a comment
+            2 1 3 0
+            1 3 0
+            0
+            \"Alice\"
+            \"Bob Smith\"
+            \"Carol\"
+            \"Test Election\"",
+        )
+        .unwrap();
+
+        assert_eq!(
+            election,
+            BltElection {
+                title: "Test Election".to_string(),
+                candidates: strings(&["Alice", "Bob Smith", "Carol"]),
+                num_seats: 1,
+                ballots: vec![
+                    strings(&["Alice", "Carol"]),
+                    strings(&["Alice", "Carol"]),
+                    strings(&["Carol"]),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_withdrawn_candidates() {
+        let election = parse("3 1 -2 1 2 1 0 1 2 0 0 \"A\" \"B\" \"C\" \"Title\"").unwrap();
+
+        assert_eq!(election.candidates, strings(&["A", "C"]));
+        assert_eq!(election.ballots, vec![strings(&["A"])]);
+    }
+
+    #[test]
+    fn parse_invalid_blt() {
+        for invalid in [
+            "",
+            "3",
+            "3 1 1 4 0 0 \"A\" \"B\" \"C\" \"Title\"",
+            "3 1 1 1 1 0 0 \"A\" \"B\" \"C\" \"Title\"",
+            "3 1 1 1=2 0 0 \"A\" \"B\" \"C\" \"Title\"",
+            "3 1 1 1 0 0 \"A\" \"B\"",
+            "3 1 1 1 0 0 \"A\" \"B\" \"C\"",
+            "3 1 1 1 0 0 \"A\" \"B\" \"C",
+            "3 1 100001 1 0 0 \"A\" \"B\" \"C\" \"Title\"",
+            "18446744073709551615 1 0",
+            "3 0 0 \"A\" \"B\" \"C\" \"Title\"",
+            "3 4 0 \"A\" \"B\" \"C\" \"Title\"",
+            "3 1 -9223372036854775808 0 \"A\" \"B\" \"C\" \"Title\"",
+            "3 1 -0 0 \"A\" \"B\" \"C\" \"Title\"",
+            "3 1 -99 0 \"A\" \"B\" \"C\" \"Title\"",
+        ] {
+            assert!(parse(invalid).is_err(), "'{}' was parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn write_collapses_identical_ballots() {
+        let mut poll = Poll::new(
+            None,
+            "Test Election".to_string(),
+            "".to_string(),
+            strings(&["Alice", "Bob", "Carol"]),
+            Duration::from_secs(1),
+            1,
+            false,
+        )
+        .unwrap();
+        for ballot in [&["Carol"][..], &["Alice", "Carol"], &["Alice", "Carol"]] {
+            poll.votes.push(RankedChoiceVote {
+                ranked_choices: strings(ballot),
+                voter_ip_hash: None,
+            });
+        }
+
+        let blt = write(&poll);
+        assert_eq!(
+            blt,
+            "3 1\n2 1 3 0\n1 3 0\n0\n\"Alice\"\n\"Bob\"\n\"Carol\"\n\"Test Election\"\n"
+        );
+
+        let election = parse(&blt).unwrap();
+        assert_eq!(election.candidates, poll.candidates);
+        assert_eq!(election.num_seats, 1);
+        assert_eq!(election.ballots.len(), 3);
+    }
+}
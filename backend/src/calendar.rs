@@ -0,0 +1,209 @@
+//! iCalendar (RFC 5545) exports of polls, so voters can add the deadline to their calendars.
+
+use std::time::SystemTime;
+
+use rocket::http::ContentType;
+use rocket::request::FromParam;
+use rocket::State;
+
+use crate::config::Config;
+use crate::error::{ErrorKind, VisibleError};
+use crate::html::rfc3339;
+use crate::metrics::TimedConnection;
+use crate::poll::Poll;
+
+/// How long before a poll closes the reminder goes off, in minutes
+const ALARM_MINUTES: u64 = 60;
+/// The longest a content line can be, in octets, before it must be folded
+const MAX_LINE_LENGTH: usize = 75;
+
+/// Returns the routes that should be made available
+pub fn routes() -> Vec<rocket::Route> {
+    routes![calendar]
+}
+
+/// A path segment of the form `<pollid>.ics`.
+///
+/// Rocket can't match part of a segment, so this is how `/poll/<pollid>.ics` is told apart from
+/// `/poll/<pollid>`.
+pub struct IcsFile<'r>(&'r str);
+
+impl<'r> FromParam<'r> for IcsFile<'r> {
+    type Error = &'r str;
+
+    fn from_param(param: &'r str) -> Result<Self, Self::Error> {
+        match param.strip_suffix(".ics") {
+            Some(id) if !id.is_empty() => Ok(IcsFile(id)),
+            _ => Err(param),
+        }
+    }
+}
+
+/// Formats a UNIX timestamp (in seconds) as an iCalendar UTC date-time, like `20220131T120000Z`
+fn date_time(timestamp: u64) -> String {
+    rfc3339(timestamp).replace(&['-', ':'][..], "")
+}
+
+/// Escapes text for use in a property value
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace(&['\r', '\n'][..], "\\n")
+}
+
+/// Folds a content line so that no line is longer than 75 octets, without splitting characters
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 8);
+    let mut line_length = 0;
+    for c in line.chars() {
+        if line_length + c.len_utf8() > MAX_LINE_LENGTH {
+            folded.push_str("\r\n ");
+            // The space that starts a continuation line counts towards its length
+            line_length = 1;
+        }
+        folded.push(c);
+        line_length += c.len_utf8();
+    }
+    folded
+}
+
+/// Builds a calendar with one event covering the time the poll is open
+fn ics(poll: &Poll, poll_url: &str, host: &str, now: u64) -> String {
+    let mut description = poll.description.trim().to_string();
+    if !description.is_empty() {
+        description.push_str("\n\n");
+    }
+    description.push_str(&format!("Vote at {}", poll_url));
+
+    let lines = [
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//BetterPoll//BetterPoll//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:poll-{}@{}", poll.id, host),
+        format!("DTSTAMP:{}", date_time(now)),
+        format!("DTSTART:{}", date_time(poll.creation_time)),
+        format!("DTEND:{}", date_time(poll.end_time)),
+        format!("SUMMARY:{}", escape(&format!("Poll: {}", poll.title))),
+        format!("DESCRIPTION:{}", escape(&description)),
+        format!("URL:{}", poll_url),
+        "BEGIN:VALARM".to_string(),
+        "ACTION:DISPLAY".to_string(),
+        format!(
+            "DESCRIPTION:{}",
+            escape(&format!("Voting in \"{}\" closes soon", poll.title))
+        ),
+        format!("TRIGGER;RELATED=END:-PT{}M", ALARM_MINUTES),
+        "END:VALARM".to_string(),
+        "END:VEVENT".to_string(),
+        "END:VCALENDAR".to_string(),
+    ];
+
+    let mut calendar = String::new();
+    for line in &lines {
+        calendar.push_str(&fold(line));
+        calendar.push_str("\r\n");
+    }
+    calendar
+}
+
+#[get("/poll/<file>", rank = 1)]
+async fn calendar(
+    mut conn: TimedConnection,
+    config: &State<Config>,
+    file: IcsFile<'_>,
+) -> Result<(ContentType, String), ErrorKind> {
+    let IcsFile(pollid) = file;
+    let poll = match conn.get_poll_by_id(pollid.to_string()).await? {
+        Some(poll) => poll,
+        None => return Err(VisibleError::PollNotFound(pollid.to_string()).into()),
+    };
+
+    let host = config
+        .frontend_url
+        .rsplit("://")
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches('/');
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    Ok((
+        ContentType::Calendar,
+        ics(&poll, &config.poll_url(pollid), host, now),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn ics_files() {
+        assert_eq!(
+            IcsFile::from_param("abc.ics").ok().map(|f| f.0),
+            Some("abc")
+        );
+        assert_eq!(
+            IcsFile::from_param("a.b.ics").ok().map(|f| f.0),
+            Some("a.b")
+        );
+        assert!(IcsFile::from_param(".ics").is_err());
+        assert!(IcsFile::from_param("abc").is_err());
+    }
+
+    #[test]
+    fn escaping_and_folding() {
+        assert_eq!(
+            escape("Lunch; dinner, or\nboth\\neither"),
+            "Lunch\\; dinner\\, or\\nboth\\\\neither"
+        );
+
+        let line = format!("DESCRIPTION:{}", "é".repeat(50));
+        let folded = fold(&line);
+        for part in folded.split("\r\n") {
+            assert!(part.len() <= MAX_LINE_LENGTH);
+        }
+        assert_eq!(folded.replace("\r\n ", ""), line);
+        assert_eq!(fold("SHORT:line"), "SHORT:line");
+    }
+
+    #[test]
+    fn calendars() {
+        let mut poll = Poll::new(
+            Some("lunch".to_string()),
+            "Lunch, today".to_string(),
+            "Where?".to_string(),
+            vec!["Pizza".to_string(), "Curry".to_string()],
+            Duration::from_secs(3600),
+            1,
+            false,
+        )
+        .unwrap();
+        poll.creation_time = 1_643_630_400;
+        poll.end_time = 1_643_634_000;
+
+        let calendar = ics(
+            &poll,
+            "https://betterpoll.cc/poll/lunch",
+            "betterpoll.cc",
+            1_643_630_400,
+        );
+        assert!(calendar.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(calendar.ends_with("END:VEVENT\r\nEND:VCALENDAR\r\n"));
+        assert!(calendar.contains("\r\nUID:poll-lunch@betterpoll.cc\r\n"));
+        assert!(calendar.contains("\r\nDTSTART:20220131T120000Z\r\n"));
+        assert!(calendar.contains("\r\nDTEND:20220131T130000Z\r\n"));
+        assert!(calendar.contains("\r\nSUMMARY:Poll: Lunch\\, today\r\n"));
+        assert!(calendar
+            .contains("\r\nDESCRIPTION:Where?\\n\\nVote at https://betterpoll.cc/poll/lunch\r\n"));
+        assert!(calendar.contains("\r\nTRIGGER;RELATED=END:-PT60M\r\n"));
+    }
+}
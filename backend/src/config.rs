@@ -0,0 +1,189 @@
+//! Server configuration, read from `Rocket.toml`.
+//!
+//! All keys except `server_secret` are optional and go in the same section as the database
+//! configuration.
+
+use std::collections::HashMap;
+
+use ipnet::IpNet;
+use rocket::fairing::AdHoc;
+use rocket::serde::json::{json, Value};
+use rocket::serde::Deserialize;
+
+use crate::logging::{self, Level};
+use crate::ratelimit::{default_rate_limits, RateLimit};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// Secret used for keyed hashes, such as ballot receipts, voter IP hashes and invitations.
+    ///
+    /// Release builds won't start without it. Debug builds generate a random secret every time
+    /// the server starts instead, so double voting can't be detected across restarts.
+    #[serde(default)]
+    pub server_secret: String,
+    /// Reverse proxies (in CIDR notation) whose `Forwarded` and `X-Forwarded-For` headers are
+    /// trusted to contain the client's IP address
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// When IP protection is on, IPv4 addresses in the same network of this size count as one voter
+    #[serde(default = "default_ipv4_prefix_length")]
+    pub ipv4_prefix_length: u8,
+    /// When IP protection is on, IPv6 addresses in the same network of this size count as one voter
+    #[serde(default = "default_ipv6_prefix_length")]
+    pub ipv6_prefix_length: u8,
+    /// Rate limits for each client, keyed by route name.
+    ///
+    /// If this is set, it replaces all of the defaults, so routes that aren't listed aren't limited.
+    #[serde(default = "default_rate_limits")]
+    pub rate_limits: HashMap<String, RateLimit>,
+    /// Number of leading zero bits required in proof-of-work solutions.
+    ///
+    /// Proof of work is required to create polls and to vote in polls without IP protection;
+    /// 0 turns it off. Each extra bit doubles the work a client has to do.
+    #[serde(default)]
+    pub pow_difficulty: u8,
+    /// Lets webhooks be sent to loopback and private addresses, such as a receiver on the same
+    /// machine for testing. Anyone can add a webhook, so this shouldn't be set in production.
+    #[serde(default)]
+    pub allow_private_webhooks: bool,
+    /// Where the frontend is hosted, used to link to polls
+    #[serde(default = "default_frontend_url")]
+    pub frontend_url: String,
+    /// Where this server can be reached publicly, used to link to its own pages
+    #[serde(default = "default_api_url")]
+    pub api_url: String,
+    /// The SMTP relay that emails are sent through.
+    ///
+    /// If it isn't set, features that need email (invitation-only polls and results emails) are
+    /// turned off.
+    #[serde(default)]
+    pub smtp: Option<SmtpConfig>,
+}
+
+/// How to connect to an SMTP relay
+#[derive(Debug, Clone, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    /// Credentials for the relay; if there's no username, emails are sent without logging in
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The mailbox that emails are sent from, like `BetterPoll <polls@example.com>`
+    pub from: String,
+}
+
+/// How the connection to an SMTP relay is secured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plain text, for local stand-ins like MailHog
+    None,
+    /// Upgrade to TLS with `STARTTLS`, which must be supported
+    #[default]
+    StartTls,
+    /// TLS from the start ("implicit TLS", usually on port 465)
+    Tls,
+}
+
+/// The server secret that `Rocket.example.toml` ships with, which is left empty so that it has to
+/// be set
+const EXAMPLE_SECRET: &str = "";
+
+impl Config {
+    /// The frontend's page for a poll
+    pub fn poll_url(&self, poll_id: &str) -> String {
+        format!(
+            "{}/poll/{}",
+            self.frontend_url.trim_end_matches('/'),
+            poll_id
+        )
+    }
+
+    /// Checks that the server secret has been set to something other than the example's
+    pub fn check_secret(&self) -> Result<(), &'static str> {
+        if self.server_secret == EXAMPLE_SECRET {
+            Err("No server_secret is configured in Rocket.toml; set it to a long random string.")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Returns a fairing that reads the configuration and makes it available as managed state.
+///
+/// If the server secret isn't usable, release builds refuse to start, and debug builds use a
+/// random one.
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Configuration", |rocket| async {
+        let mut config: Config = match rocket.figment().extract() {
+            Ok(config) => config,
+            Err(e) => {
+                logging::log(
+                    Level::Error,
+                    "The configuration in Rocket.toml isn't valid.",
+                    json!({ "error": e.to_string() }),
+                );
+                return Err(rocket);
+            }
+        };
+        if let Err(message) = config.check_secret() {
+            if !cfg!(debug_assertions) {
+                logging::log(Level::Error, message, Value::Null);
+                return Err(rocket);
+            }
+            logging::log(
+                Level::Warn,
+                &format!(
+                    "{} Using a random one, since this is a debug build.",
+                    message
+                ),
+                Value::Null,
+            );
+            config.server_secret = format!("{:032x}", rand::random::<u128>());
+        }
+        Ok(rocket.manage(config))
+    })
+}
+
+fn default_ipv4_prefix_length() -> u8 {
+    32
+}
+
+fn default_ipv6_prefix_length() -> u8 {
+    64
+}
+
+fn default_frontend_url() -> String {
+    "https://betterpoll.cc".to_string()
+}
+
+fn default_api_url() -> String {
+    "https://api.betterpoll.cc/v1".to_string()
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(secret: &str) -> Config {
+        rocket::Config::figment()
+            .merge(("server_secret", secret))
+            .extract()
+            .unwrap()
+    }
+
+    #[test]
+    fn secrets() {
+        assert!(config(EXAMPLE_SECRET).check_secret().is_err());
+        assert!(config("0123456789abcdef0123456789abcdef")
+            .check_secret()
+            .is_ok());
+    }
+}
@@ -0,0 +1,78 @@
+//! Keyed hashing helpers, and comparing secrets
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Computes the hex-encoded HMAC-SHA256 of `parts` using `secret` as the key.
+///
+/// Each part is prefixed with its length, so `["ab", "c"]` and `["a", "bc"]` hash differently.
+pub fn keyed_hash(secret: &str, parts: &[&[u8]]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    for part in parts {
+        mac.update(&(part.len() as u64).to_be_bytes());
+        mac.update(part);
+    }
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Computes the hex-encoded HMAC-SHA256 of `message`, in the standard way so that other programs
+/// can check it, such as the receivers of webhooks
+pub fn sign(secret: &str, message: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(message);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Compares two strings in constant time, so that secrets such as signatures and tokens can't be
+/// guessed byte by byte
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyed_hash_depends_on_secret() {
+        assert_ne!(
+            keyed_hash("secret1", &[b"data"]),
+            keyed_hash("secret2", &[b"data"])
+        );
+        assert_eq!(
+            keyed_hash("secret1", &[b"data"]),
+            keyed_hash("secret1", &[b"data"])
+        );
+    }
+
+    #[test]
+    fn keyed_hash_parts_are_unambiguous() {
+        assert_ne!(
+            keyed_hash("secret", &[b"ab", b"c"]),
+            keyed_hash("secret", &[b"a", b"bc"])
+        );
+    }
+
+    #[test]
+    fn sign_is_standard_hmac() {
+        // From RFC 4231
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn constant_time_comparison() {
+        assert!(constant_time_eq("token", "token"));
+        assert!(!constant_time_eq("token", "tokem"));
+        assert!(!constant_time_eq("token", "token2"));
+        assert!(constant_time_eq("", ""));
+    }
+}
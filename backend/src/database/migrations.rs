@@ -0,0 +1,80 @@
+//! Database schema migrations
+//!
+//! New databases are set up with `schema.sql`; existing ones are upgraded one version at a time
+//! with the scripts in `migrations/`, each of which updates the version in `db_info`.
+
+use crate::config::Config;
+use crate::error::ErrorKind;
+
+use super::postgres::SCHEMA_VERSION;
+
+/// The schema for a new database
+const SCHEMA: &str = include_str!("../../schema.sql");
+
+/// The scripts that upgrade the database to each version
+pub const MIGRATIONS: &[(i32, &str)] = &[
+    (2, include_str!("../../migrations/002_ballot_tokens.sql")),
+    (3, include_str!("../../migrations/003_ballot_receipts.sql")),
+    (4, include_str!("../../migrations/004_admin_tokens.sql")),
+    (
+        5,
+        include_str!("../../migrations/005_optional_voter_ip.sql"),
+    ),
+    (6, include_str!("../../migrations/006_hash_voter_ips.sql")),
+    (
+        7,
+        include_str!("../../migrations/007_provisional_results.sql"),
+    ),
+    (8, include_str!("../../migrations/008_webhooks.sql")),
+    (9, include_str!("../../migrations/009_poll_directory.sql")),
+    (10, include_str!("../../migrations/010_email.sql")),
+];
+
+/// Brings the database up to date, creating the schema if it's empty.
+///
+/// The server's configuration is needed by migrations that hash data the same way as the server.
+/// Returns the versions of the migrations that were applied.
+pub fn migrate(c: &mut postgres::Client, config: &Config) -> Result<Vec<i32>, ErrorKind> {
+    let has_schema: bool = c
+        .query_one("SELECT to_regclass('db_info') IS NOT NULL AS exists", &[])?
+        .try_get("exists")?;
+    if !has_schema {
+        c.batch_execute(SCHEMA)?;
+        return Ok(vec![SCHEMA_VERSION]);
+    }
+
+    let version: i32 = c
+        .query_one("SELECT version FROM db_info LIMIT 1", &[])?
+        .try_get("version")?;
+    c.execute(
+        "SELECT set_config('betterpoll.server_secret', $1, false),
+        set_config('betterpoll.ipv4_prefix_length', $2, false),
+        set_config('betterpoll.ipv6_prefix_length', $3, false)",
+        &[
+            &config.server_secret,
+            &config.ipv4_prefix_length.to_string(),
+            &config.ipv6_prefix_length.to_string(),
+        ],
+    )?;
+
+    let mut applied = vec![];
+    for (migration_version, script) in MIGRATIONS {
+        if *migration_version > version {
+            c.batch_execute(script)?;
+            applied.push(*migration_version);
+        }
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_contiguous() {
+        let versions: Vec<i32> = MIGRATIONS.iter().map(|(version, _)| *version).collect();
+        let expected: Vec<i32> = (2..=SCHEMA_VERSION).collect();
+        assert_eq!(versions, expected);
+    }
+}
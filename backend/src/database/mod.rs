@@ -1,4 +1,5 @@
 // Generic database code
+pub mod migrations;
 pub mod postgres;
 
 // Generic database trait; an implementation be used with Rocket for fun stuff
@@ -4,118 +4,361 @@
 
 use std::time::{Duration, SystemTime};
 
+use postgres::types::ToSql;
+use rocket::serde::json::json;
+use rocket::{Build, Phase, Rocket};
+use rocket_sync_db_pools::ConnectionPool;
+
 use crate::{
+    directory::{self, Status},
     error::{ErrorKind, InternalError},
     poll::*,
+    webhooks,
 };
 
-#[cfg_attr(test, database("test_db"))]
-#[cfg_attr(not(test), database("production_db"))]
-pub struct PostgresConnection(pub postgres::Client);
+/// The version of the schema in `schema.sql`, which the database should have
+pub const SCHEMA_VERSION: i32 = 10;
 
-impl PostgresConnection {
-    pub async fn get_poll_by_id(&mut self, id: String) -> Result<Option<Poll>, ErrorKind> {
-        let cloned_id = id.clone();
-        let poll_row = match self
-            .run(move |c| c.query("SELECT * FROM polls WHERE id = $1 LIMIT 1", &[&cloned_id]))
-            .await?
-            .pop()
-        {
-            Some(row) => row,
-            None => return Ok(None), // No poll by that ID
-        };
+/// Gets a poll and its ballots, tallying it if it has ended
+pub fn get_poll(c: &mut postgres::Client, id: &str) -> Result<Option<Poll>, ErrorKind> {
+    let id = id.to_string();
+    let poll_row = match c
+        .query("SELECT * FROM polls WHERE id = $1 LIMIT 1", &[&id])?
+        .pop()
+    {
+        Some(row) => row,
+        None => return Ok(None), // No poll by that ID
+    };
 
-        let method_discrim: i32 = poll_row.try_get("method")?;
-        let method = match method_discrim {
-            0 => VotingMethod::Schulze,
-            _ => {
-                return Err(ErrorKind::Internal(
-                    InternalError::UnknownVotingMethodDiscriminant(method_discrim),
-                ))
-            }
-        };
-        let num_winners: i32 = poll_row.try_get("num_winners")?;
-
-        let num_winners = match num_winners.try_into() {
-            Ok(num_winners) => num_winners,
-            Err(e) => {
-                return Err(ErrorKind::Internal(InternalError::InvalidNumWinners(
-                    num_winners,
-                    e,
-                )))
-            }
-        };
+    let method_discrim: i32 = poll_row.try_get("method")?;
+    let method = match method_discrim {
+        0 => VotingMethod::Schulze,
+        _ => {
+            return Err(ErrorKind::Internal(
+                InternalError::UnknownVotingMethodDiscriminant(method_discrim),
+            ))
+        }
+    };
+    let provisional_results_discrim: i32 = poll_row.try_get("provisional_results")?;
+    let provisional_results = match provisional_results_discrim {
+        0 => ProvisionalResults::Hidden,
+        1 => ProvisionalResults::VisibleToAdmin,
+        2 => ProvisionalResults::Public,
+        _ => {
+            return Err(ErrorKind::Internal(
+                InternalError::UnknownProvisionalResultsDiscriminant(provisional_results_discrim),
+            ))
+        }
+    };
+    let num_winners: i32 = poll_row.try_get("num_winners")?;
 
-        let cloned_id = id.clone();
-        let votes_rows = self
-            .run(move |c| {
-                c.query(
-                    "SELECT preferences, voter_ip FROM votes WHERE poll_id = $1",
-                    &[&cloned_id],
-                )
-            })
-            .await?;
-        let mut votes = Vec::with_capacity(votes_rows.len());
-        for row in votes_rows {
-            let ranked_choices = row.try_get("preferences")?;
-            let voter_ip = row.try_get("voter_ip")?;
-            votes.push(RankedChoiceVote {
-                ranked_choices,
-                voter_ip,
-            });
+    let num_winners = match num_winners.try_into() {
+        Ok(num_winners) => num_winners,
+        Err(e) => {
+            return Err(ErrorKind::Internal(InternalError::InvalidNumWinners(
+                num_winners,
+                e,
+            )))
         }
+    };
 
-        let creation_systime: SystemTime = poll_row.try_get("created_at")?;
-        let creation_time = match creation_systime.duration_since(SystemTime::UNIX_EPOCH) {
-            Ok(duration) => duration.as_secs(),
-            Err(e) => {
-                return Err(ErrorKind::Internal(
-                    InternalError::CouldNotConvertDBTimeToUNIX(e, id),
-                ))
-            }
-        };
+    let votes_rows = c.query(
+        "SELECT preferences, voter_ip_hash FROM votes WHERE poll_id = $1",
+        &[&id],
+    )?;
+    let mut votes = Vec::with_capacity(votes_rows.len());
+    for row in votes_rows {
+        let ranked_choices = row.try_get("preferences")?;
+        let voter_ip_hash = row.try_get("voter_ip_hash")?;
+        votes.push(RankedChoiceVote {
+            ranked_choices,
+            voter_ip_hash,
+        });
+    }
 
-        let end_systime: SystemTime = poll_row.try_get("expires_at")?;
-        let end_time = match end_systime.duration_since(SystemTime::UNIX_EPOCH) {
-            Ok(duration) => duration.as_secs(),
-            Err(e) => {
-                return Err(ErrorKind::Internal(
-                    InternalError::CouldNotConvertDBTimeToUNIX(e, id),
-                ))
-            }
-        };
+    let creation_systime: SystemTime = poll_row.try_get("created_at")?;
+    let creation_time = match creation_systime.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(e) => {
+            return Err(ErrorKind::Internal(
+                InternalError::CouldNotConvertDBTimeToUNIX(e, id),
+            ))
+        }
+    };
 
-        let mut poll = Poll {
-            id,
-            title: poll_row.try_get("title")?,
-            description: poll_row.try_get("description")?,
-            candidates: poll_row.try_get("candidates")?,
-            creation_time,
-            end_time,
-            prohibit_double_vote_by_ip: poll_row.try_get("prohibit_double_vote_by_ip")?,
-            num_winners,
-            winners: None,
-            votes,
-            method,
-        };
-        if poll.end_time
-            < std::time::SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .expect("can't find out how long it was since the UNIX epoch")
-                .as_secs()
-        {
-            poll.finish()?;
+    let end_systime: SystemTime = poll_row.try_get("expires_at")?;
+    let end_time = match end_systime.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(e) => {
+            return Err(ErrorKind::Internal(
+                InternalError::CouldNotConvertDBTimeToUNIX(e, id),
+            ))
         }
+    };
 
-        Ok(Some(poll))
+    let mut poll = Poll {
+        id,
+        title: poll_row.try_get("title")?,
+        description: poll_row.try_get("description")?,
+        candidates: poll_row.try_get("candidates")?,
+        creation_time,
+        end_time,
+        prohibit_double_vote_by_ip: poll_row.try_get("prohibit_double_vote_by_ip")?,
+        admin_token: poll_row.try_get("admin_token")?,
+        provisional_results,
+        listed: poll_row.try_get("listed")?,
+        invite_only: poll_row.try_get("invite_only")?,
+        creator_email: poll_row.try_get("creator_email")?,
+        num_winners,
+        winners: None,
+        votes,
+        method,
+    };
+    if poll.has_ended() {
+        poll.finish()?;
     }
 
-    pub async fn add_poll(&mut self, poll: Poll) -> Result<(), ErrorKind> {
+    Ok(Some(poll))
+}
+
+/// A short description of a poll, as shown in lists of polls
+#[derive(Debug, Clone)]
+pub struct PollSummary {
+    pub id: String,
+    pub title: String,
+    pub created_at: SystemTime,
+    pub expires_at: SystemTime,
+    pub num_votes: i64,
+}
+
+/// Lists the most recently created polls, optionally only those whose ID or title contains `search`
+pub fn list_polls(
+    c: &mut postgres::Client,
+    search: Option<&str>,
+    limit: i64,
+) -> Result<Vec<PollSummary>, ErrorKind> {
+    let pattern = search.map(|term| format!("%{}%", term));
+    let rows = c.query(
+        "SELECT id, title, created_at, expires_at,
+            (SELECT COUNT(*) FROM votes WHERE votes.poll_id = polls.id) AS num_votes
+        FROM polls
+        WHERE $1::TEXT IS NULL OR id ILIKE $1 OR title ILIKE $1
+        ORDER BY created_at DESC, id DESC
+        LIMIT $2",
+        &[&pattern, &limit],
+    )?;
+
+    let mut polls = Vec::with_capacity(rows.len());
+    for row in rows {
+        polls.push(PollSummary {
+            id: row.try_get("id")?,
+            title: row.try_get("title")?,
+            created_at: row.try_get("created_at")?,
+            expires_at: row.try_get("expires_at")?,
+            num_votes: row.try_get("num_votes")?,
+        });
+    }
+    Ok(polls)
+}
+
+/// Deletes a poll along with its ballots, their history, its webhooks, its invitations,
+/// and any of its emails that haven't been sent.
+/// The webhooks are sent a `poll_deleted` event. Returns `false` if there is no such poll.
+pub fn delete_poll(c: &mut postgres::Client, id: &str) -> Result<bool, ErrorKind> {
+    let payload = webhooks::payload(webhooks::POLL_DELETED, id, json!({})).to_string();
+    let mut transaction = c.transaction()?;
+    transaction.execute(
+        QUEUE_WEBHOOK_EVENT,
+        &[&id, &webhooks::POLL_DELETED, &payload],
+    )?;
+    transaction.execute("DELETE FROM webhooks WHERE poll_id = $1", &[&id])?;
+    transaction.execute("DELETE FROM vote_changes WHERE poll_id = $1", &[&id])?;
+    transaction.execute("DELETE FROM votes WHERE poll_id = $1", &[&id])?;
+    transaction.execute("DELETE FROM invitations WHERE poll_id = $1", &[&id])?;
+    transaction.execute(
+        "DELETE FROM emails WHERE poll_id = $1 AND sent_at IS NULL",
+        &[&id],
+    )?;
+    let deleted = transaction.execute("DELETE FROM polls WHERE id = $1", &[&id])?;
+    if deleted > 0 {
+        transaction.commit()?;
+    }
+    Ok(deleted > 0)
+}
+
+/// Queues an event for delivery to every webhook of a poll.
+/// The parameters are the poll ID, the event, and the payload.
+const QUEUE_WEBHOOK_EVENT: &str = "INSERT INTO webhook_deliveries
+    (webhook_id, poll_id, url, secret, event, payload)
+    SELECT id, poll_id, url, secret, $2, $3 FROM webhooks WHERE poll_id = $1";
+
+/// Queues an email. The parameters are the poll ID, the recipient, the subject, and the body.
+const QUEUE_EMAIL: &str = "INSERT INTO emails (poll_id, recipient, subject, body)
+    VALUES ($1, $2, $3, $4)";
+
+/// Adds a ballot. The parameters are the poll ID, the ballot's token, the voter's IP hash,
+/// the ranked choices, and the receipt and its nonce.
+const INSERT_VOTE: &str =
+    "INSERT INTO votes (poll_id, token, voter_ip_hash, preferences, receipt, receipt_nonce)
+    VALUES ($1, $2, $3, $4, $5, $6)";
+
+/// How many ballots are added by each statement when a poll is created with ballots already cast
+const BALLOT_BATCH_SIZE: usize = 1000;
+
+/// Adds ballots without receipts, such as ones imported from a file, in a single statement
+fn insert_ballots(
+    transaction: &mut postgres::Transaction,
+    poll_id: &str,
+    votes: &[RankedChoiceVote],
+) -> Result<(), postgres::Error> {
+    let tokens: Vec<String> = votes.iter().map(|_| new_secret_token()).collect();
+    let mut query =
+        "INSERT INTO votes (poll_id, token, voter_ip_hash, preferences) VALUES ".to_string();
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&poll_id];
+    for (i, (vote, token)) in votes.iter().zip(&tokens).enumerate() {
+        if i > 0 {
+            query.push_str(", ");
+        }
+        let first = 3 * i + 2;
+        query.push_str(&format!("($1, ${}, ${}, ${})", first, first + 1, first + 2));
+        params.push(token);
+        params.push(&vote.voter_ip_hash);
+        params.push(&vote.ranked_choices);
+    }
+    transaction.execute(query.as_str(), &params)?;
+    Ok(())
+}
+
+/// Converts a time from the database to seconds since the UNIX epoch
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// A poll shown in the public directory
+#[derive(Debug, Clone)]
+pub struct ListedPoll {
+    pub id: String,
+    pub title: String,
+    pub end_time: u64,
+    pub num_votes: i64,
+    /// Where a page that ends with this poll should be continued from
+    pub cursor: directory::Cursor,
+}
+
+/// An email that hasn't been queued yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedEmail {
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// An email in the queue
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub id: i64,
+    pub poll_id: String,
+    pub email: QueuedEmail,
+    /// How many times sending it has been attempted
+    pub attempts: i32,
+}
+
+/// A URL that is notified about events in a poll
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    /// Seconds since the Epoch
+    pub created_at: u64,
+}
+
+/// A request that has been or will be sent to a webhook
+#[derive(Debug, Clone)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub url: String,
+    pub secret: String,
+    pub event: String,
+    pub payload: String,
+    pub attempts: i32,
+    /// Seconds since the Epoch
+    pub created_at: u64,
+    pub next_attempt_at: Option<u64>,
+    pub delivered_at: Option<u64>,
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+}
+
+impl WebhookDelivery {
+    fn from_row(row: &postgres::Row) -> Result<Self, ErrorKind> {
+        let next_attempt_at: Option<SystemTime> = row.try_get("next_attempt_at")?;
+        let delivered_at: Option<SystemTime> = row.try_get("delivered_at")?;
+        Ok(Self {
+            id: row.try_get("id")?,
+            webhook_id: row.try_get("webhook_id")?,
+            url: row.try_get("url")?,
+            secret: row.try_get("secret")?,
+            event: row.try_get("event")?,
+            payload: row.try_get("payload")?,
+            attempts: row.try_get("attempts")?,
+            created_at: unix_seconds(row.try_get("created_at")?),
+            next_attempt_at: next_attempt_at.map(unix_seconds),
+            delivered_at: delivered_at.map(unix_seconds),
+            last_status: row.try_get("last_status")?,
+            last_error: row.try_get("last_error")?,
+        })
+    }
+}
+
+#[cfg_attr(test, database("test_db"))]
+#[cfg_attr(not(test), database("production_db"))]
+pub struct PostgresConnection(pub postgres::Client);
+
+/// Hands out connections to code that runs outside of requests, such as the background jobs, so
+/// that it only holds one while it needs it.
+///
+/// Connections are only handed out given a `Rocket`, and the launched one can't be kept by tasks
+/// that outlive its fairings, so this keeps one that holds nothing but the connection pool.
+pub struct ConnectionSource(Rocket<Build>);
+
+impl ConnectionSource {
+    pub async fn new<P: Phase>(rocket: &Rocket<P>) -> Option<Self> {
+        let pool = ConnectionPool::<PostgresConnection, postgres::Client>::get_pool(rocket).await?;
+        Some(ConnectionSource(
+            rocket::custom(rocket.figment().clone()).manage(pool),
+        ))
+    }
+
+    /// Takes a connection from the pool, or returns `None` if none is free in time
+    pub async fn get(&self) -> Option<PostgresConnection> {
+        PostgresConnection::get_one(&self.0).await
+    }
+}
+
+impl PostgresConnection {
+    pub async fn get_poll_by_id(&mut self, id: String) -> Result<Option<Poll>, ErrorKind> {
+        self.run(move |c| get_poll(c, &id)).await
+    }
+
+    /// Adds a poll, along with any ballots already cast in it and its invitations.
+    ///
+    /// `invitations` pairs the hash of each invitation's token with the email that sends it.
+    pub async fn add_poll(
+        &mut self,
+        poll: Poll,
+        invitations: Vec<(String, QueuedEmail)>,
+    ) -> Result<(), ErrorKind> {
         let method_discrim: i32 = match poll.method {
             VotingMethod::Schulze => 0,
         };
-        let id = poll.id.clone();
-
+        let provisional_results_discrim: i32 = match poll.provisional_results {
+            ProvisionalResults::Hidden => 0,
+            ProvisionalResults::VisibleToAdmin => 1,
+            ProvisionalResults::Public => 2,
+        };
         let creation_time = match std::time::SystemTime::UNIX_EPOCH
             .checked_add(Duration::from_secs(poll.creation_time))
         {
@@ -139,8 +382,11 @@ impl PostgresConnection {
             }
         };
 
-        self.run(move |c| {
-            c.query(
+        // Everything is added together, so a failure can't leave a poll with only some of its
+        // ballots or invitations
+        self.run(move |c| -> Result<(), postgres::Error> {
+            let mut transaction = c.transaction()?;
+            transaction.execute(
                 "INSERT INTO polls (
                 id,
                 title,
@@ -150,8 +396,13 @@ impl PostgresConnection {
                 prohibit_double_vote_by_ip,
                 expires_at,
                 num_winners,
-                method
-            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+                method,
+                admin_token,
+                provisional_results,
+                listed,
+                invite_only,
+                creator_email
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)",
                 &[
                     &poll.id,
                     &poll.title,
@@ -162,52 +413,600 @@ impl PostgresConnection {
                     &end_time,
                     &(poll.num_winners as i32),
                     &method_discrim,
+                    &poll.admin_token,
+                    &provisional_results_discrim,
+                    &poll.listed,
+                    &poll.invite_only,
+                    &poll.creator_email,
                 ],
-            )
+            )?;
+            for batch in poll.votes.chunks(BALLOT_BATCH_SIZE) {
+                insert_ballots(&mut transaction, &poll.id, batch)?;
+            }
+            for (token_hash, email) in &invitations {
+                transaction.execute(
+                    "INSERT INTO invitations (poll_id, token_hash) VALUES ($1, $2)",
+                    &[&poll.id, token_hash],
+                )?;
+                transaction.execute(
+                    QUEUE_EMAIL,
+                    &[&poll.id, &email.recipient, &email.subject, &email.body],
+                )?;
+            }
+            transaction.commit()
         })
         .await?;
 
-        for vote in poll.votes {
-            Self::add_vote_to_poll(self, id.clone(), vote).await?;
-        }
-
         Ok(())
     }
 
+    /// Adds a vote, returning the token that can be used to change or retract it
     pub async fn add_vote_to_poll(
         &mut self,
         id: String,
         vote: RankedChoiceVote,
-    ) -> Result<(), ErrorKind> {
+        receipt: Option<BallotReceipt>,
+    ) -> Result<String, ErrorKind> {
+        let token = new_secret_token();
+        let cloned_token = token.clone();
+        let (receipt, receipt_nonce) = match receipt {
+            Some(receipt) => (Some(receipt.hash), Some(receipt.nonce)),
+            None => (None, None),
+        };
         self.run(move |c| {
             c.query(
-                "INSERT INTO votes (poll_id, voter_ip, preferences) VALUES ($1, $2, $3)",
-                &[&id, &vote.voter_ip, &vote.ranked_choices],
+                INSERT_VOTE,
+                &[
+                    &id,
+                    &cloned_token,
+                    &vote.voter_ip_hash,
+                    &vote.ranked_choices,
+                    &receipt,
+                    &receipt_nonce,
+                ],
             )
         })
         .await?;
-        Ok(())
+        Ok(token)
     }
 
-    pub async fn get_total_polls(
+    /// Adds a vote to an invitation-only poll, using up the invitation whose token has the given
+    /// hash. Returns the token that can be used to change or retract the ballot, or `None` if
+    /// there is no such invitation or it has already been used.
+    pub async fn add_invited_vote(
         &mut self,
-    ) -> Result<i64, ErrorKind> {
-        let mut rows = self.run(move |c| c.query("SELECT COUNT(*) AS count FROM polls", &[])).await?;
+        id: String,
+        vote: RankedChoiceVote,
+        receipt: BallotReceipt,
+        invitation_hash: String,
+    ) -> Result<Option<String>, ErrorKind> {
+        let token = new_secret_token();
+        let cloned_token = token.clone();
+        let added = self
+            .run(move |c| -> Result<bool, postgres::Error> {
+                let mut transaction = c.transaction()?;
+                let used = transaction.execute(
+                    "UPDATE invitations SET used = TRUE
+                    WHERE poll_id = $1 AND token_hash = $2 AND NOT used",
+                    &[&id, &invitation_hash],
+                )?;
+                if used == 0 {
+                    return Ok(false);
+                }
+                transaction.execute(
+                    INSERT_VOTE,
+                    &[
+                        &id,
+                        &cloned_token,
+                        &vote.voter_ip_hash,
+                        &vote.ranked_choices,
+                        &receipt.hash,
+                        &receipt.nonce,
+                    ],
+                )?;
+                transaction.commit()?;
+                Ok(true)
+            })
+            .await?;
+        Ok(if added { Some(token) } else { None })
+    }
+
+    /// Replaces the ballot with the given token, or retracts it if `new_ballot` is `None`.
+    ///
+    /// The change is recorded in the `vote_changes` table.
+    /// Returns `false` if there is no ballot with that token in the poll.
+    pub async fn change_vote(
+        &mut self,
+        id: String,
+        token: String,
+        new_ballot: Option<(Vec<String>, BallotReceipt)>,
+    ) -> Result<bool, ErrorKind> {
+        let changed = self
+            .run(move |c| -> Result<bool, postgres::Error> {
+                let new_choices = new_ballot.as_ref().map(|(choices, _)| choices);
+                let mut transaction = c.transaction()?;
+                let old_choices: Vec<String> = match transaction.query_opt(
+                    "SELECT preferences FROM votes WHERE poll_id = $1 AND token = $2 FOR UPDATE",
+                    &[&id, &token],
+                )? {
+                    Some(row) => row.try_get("preferences")?,
+                    None => return Ok(false),
+                };
+
+                match &new_ballot {
+                    Some((choices, receipt)) => transaction.execute(
+                        "UPDATE votes SET preferences = $3, receipt = $4, receipt_nonce = $5
+                        WHERE poll_id = $1 AND token = $2",
+                        &[&id, &token, choices, &receipt.hash, &receipt.nonce],
+                    )?,
+                    None => transaction.execute(
+                        "DELETE FROM votes WHERE poll_id = $1 AND token = $2",
+                        &[&id, &token],
+                    )?,
+                };
+                transaction.execute(
+                    "INSERT INTO vote_changes (poll_id, token, old_preferences, new_preferences)
+                    VALUES ($1, $2, $3, $4)",
+                    &[&id, &token, &old_choices, &new_choices],
+                )?;
+
+                transaction.commit()?;
+                Ok(true)
+            })
+            .await?;
+        Ok(changed)
+    }
+
+    /// Gets the receipts of all ballots in a poll, sorted so that they don't reveal the voting order
+    pub async fn get_receipts(&mut self, id: String) -> Result<Vec<String>, ErrorKind> {
+        let rows = self
+            .run(move |c| {
+                c.query(
+                    "SELECT receipt FROM votes WHERE poll_id = $1 AND receipt IS NOT NULL ORDER BY receipt",
+                    &[&id],
+                )
+            })
+            .await?;
+        let mut receipts = Vec::with_capacity(rows.len());
+        for row in rows {
+            receipts.push(row.try_get("receipt")?);
+        }
+        Ok(receipts)
+    }
+
+    /// Deletes the voter IP hashes of all ballots in polls that have ended, since they're
+    /// no longer needed to detect double voting. Returns the number of ballots affected.
+    pub async fn clear_ended_poll_ip_hashes(&mut self) -> Result<u64, ErrorKind> {
+        let now = SystemTime::now();
+        let count = self
+            .run(move |c| {
+                c.execute(
+                    "UPDATE votes SET voter_ip_hash = NULL
+                    WHERE voter_ip_hash IS NOT NULL
+                    AND poll_id IN (SELECT id FROM polls WHERE expires_at <= $1)",
+                    &[&now],
+                )
+            })
+            .await?;
+        Ok(count)
+    }
+
+    pub async fn get_total_polls(&mut self) -> Result<i64, ErrorKind> {
+        let mut rows = self
+            .run(move |c| c.query("SELECT COUNT(*) AS count FROM polls", &[]))
+            .await?;
         let row = rows.pop().expect("there should be exactly one row");
         let count: i64 = row.try_get("count")?;
         Ok(count)
     }
 
-    pub async fn get_active_polls(
-        &mut self,
-    ) -> Result<i64, ErrorKind> {
+    pub async fn get_active_polls(&mut self) -> Result<i64, ErrorKind> {
         let now = SystemTime::now();
 
-        let mut rows = self.run(move |c| {
-            c.query("SELECT COUNT(*) AS count FROM polls WHERE expires_at > $1", &[&now])
-        }).await?;
+        let mut rows = self
+            .run(move |c| {
+                c.query(
+                    "SELECT COUNT(*) AS count FROM polls WHERE expires_at > $1",
+                    &[&now],
+                )
+            })
+            .await?;
         let row = rows.pop().expect("there should be exactly one row");
         let count: i64 = row.try_get("count")?;
         Ok(count)
     }
+
+    /// Counts the ballots cast in a poll
+    pub async fn count_votes(&mut self, id: String) -> Result<i64, ErrorKind> {
+        let mut rows = self
+            .run(move |c| {
+                c.query(
+                    "SELECT COUNT(*) AS count FROM votes WHERE poll_id = $1",
+                    &[&id],
+                )
+            })
+            .await?;
+        let row = rows.pop().expect("there should be exactly one row");
+        let count: i64 = row.try_get("count")?;
+        Ok(count)
+    }
+
+    /// Gets a page of the polls listed in the public directory
+    pub async fn get_listed_polls(
+        &mut self,
+        query: directory::Query,
+    ) -> Result<Vec<ListedPoll>, ErrorKind> {
+        // Sort keys are compared as whole microseconds, so that cursors round-trip exactly
+        let sort_key = format!(
+            "(EXTRACT(EPOCH FROM {}) * 1000000)::BIGINT",
+            query.sort.column()
+        );
+        let (comparison, order) = if query.sort.descending() {
+            ("<", "DESC")
+        } else {
+            (">", "ASC")
+        };
+        let sql = format!(
+            "SELECT id, title, expires_at,
+                (SELECT COUNT(*) FROM votes WHERE votes.poll_id = polls.id) AS num_votes,
+                {sort_key} AS sort_key
+            FROM polls
+            WHERE listed
+                AND ($1::TEXT IS NULL OR to_tsvector('english', title || ' ' || description)
+                    @@ plainto_tsquery('english', $1))
+                AND ($2::BOOLEAN IS NULL OR (expires_at > $3) = $2)
+                AND ($4::BIGINT IS NULL OR ({sort_key}, id) {comparison} ($4, $5::TEXT))
+            ORDER BY sort_key {order}, id {order}
+            LIMIT $6",
+            sort_key = sort_key,
+            comparison = comparison,
+            order = order,
+        );
+        let active = query.status.map(|status| status == Status::Active);
+        let now = SystemTime::now();
+        let after_key = query.after.as_ref().map(|cursor| cursor.key);
+        let after_id = query.after.map(|cursor| cursor.id);
+
+        let rows = self
+            .run(move |c| {
+                c.query(
+                    sql.as_str(),
+                    &[
+                        &query.text,
+                        &active,
+                        &now,
+                        &after_key,
+                        &after_id,
+                        &query.limit,
+                    ],
+                )
+            })
+            .await?;
+
+        let mut polls = Vec::with_capacity(rows.len());
+        for row in rows {
+            let id: String = row.try_get("id")?;
+            polls.push(ListedPoll {
+                title: row.try_get("title")?,
+                end_time: unix_seconds(row.try_get("expires_at")?),
+                num_votes: row.try_get("num_votes")?,
+                cursor: directory::Cursor {
+                    key: row.try_get("sort_key")?,
+                    id: id.clone(),
+                },
+                id,
+            });
+        }
+        Ok(polls)
+    }
+
+    /// Gets the most recently created listed polls, or, if `ended` is true,
+    /// the listed polls that most recently ended
+    pub async fn get_recent_listed_polls(
+        &mut self,
+        ended: bool,
+        limit: i64,
+    ) -> Result<Vec<Poll>, ErrorKind> {
+        let now = SystemTime::now();
+        self.run(move |c| {
+            let query = if ended {
+                "SELECT id FROM polls WHERE listed AND expires_at <= $1
+                ORDER BY expires_at DESC, id DESC LIMIT $2"
+            } else {
+                "SELECT id FROM polls WHERE listed AND created_at <= $1
+                ORDER BY created_at DESC, id DESC LIMIT $2"
+            };
+            let mut polls = vec![];
+            for row in c.query(query, &[&now, &limit])? {
+                let id: String = row.try_get("id")?;
+                if let Some(poll) = get_poll(c, &id)? {
+                    polls.push(poll);
+                }
+            }
+            Ok(polls)
+        })
+        .await
+    }
+
+    /// Gets the version of the database schema, as stored in the `db_info` table
+    pub async fn get_schema_version(&mut self) -> Result<Option<i32>, ErrorKind> {
+        let mut rows = self
+            .run(move |c| c.query("SELECT version FROM db_info LIMIT 1", &[]))
+            .await?;
+        match rows.pop() {
+            Some(row) => Ok(Some(row.try_get("version")?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Registers a webhook for a poll, returning its ID
+    pub async fn add_webhook(
+        &mut self,
+        poll_id: String,
+        url: String,
+        secret: String,
+    ) -> Result<i64, ErrorKind> {
+        let row = self
+            .run(move |c| {
+                c.query_one(
+                    "INSERT INTO webhooks (poll_id, url, secret) VALUES ($1, $2, $3) RETURNING id",
+                    &[&poll_id, &url, &secret],
+                )
+            })
+            .await?;
+        Ok(row.try_get("id")?)
+    }
+
+    /// Gets the webhooks registered for a poll, oldest first
+    pub async fn get_webhooks(&mut self, poll_id: String) -> Result<Vec<Webhook>, ErrorKind> {
+        let rows = self
+            .run(move |c| {
+                c.query(
+                    "SELECT id, url, created_at FROM webhooks WHERE poll_id = $1 ORDER BY id",
+                    &[&poll_id],
+                )
+            })
+            .await?;
+        let mut webhooks = Vec::with_capacity(rows.len());
+        for row in rows {
+            webhooks.push(Webhook {
+                id: row.try_get("id")?,
+                url: row.try_get("url")?,
+                created_at: unix_seconds(row.try_get("created_at")?),
+            });
+        }
+        Ok(webhooks)
+    }
+
+    /// Gets the most recent requests sent or to be sent to a poll's webhooks, newest first
+    pub async fn get_webhook_deliveries(
+        &mut self,
+        poll_id: String,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>, ErrorKind> {
+        let rows = self
+            .run(move |c| {
+                c.query(
+                    "SELECT * FROM webhook_deliveries WHERE poll_id = $1 ORDER BY id DESC LIMIT $2",
+                    &[&poll_id, &limit],
+                )
+            })
+            .await?;
+        rows.iter().map(WebhookDelivery::from_row).collect()
+    }
+
+    /// Queues an event for delivery to every webhook of a poll
+    pub async fn queue_webhook_event(
+        &mut self,
+        poll_id: String,
+        event: &'static str,
+        payload: String,
+    ) -> Result<(), ErrorKind> {
+        self.run(move |c| c.execute(QUEUE_WEBHOOK_EVENT, &[&poll_id, &event, &payload]))
+            .await?;
+        Ok(())
+    }
+
+    /// Gets the IDs of polls that have ended, but whose webhooks haven't been told yet
+    pub async fn get_closed_polls_to_notify(&mut self) -> Result<Vec<String>, ErrorKind> {
+        let now = SystemTime::now();
+        let rows = self
+            .run(move |c| {
+                c.query(
+                    "SELECT DISTINCT webhooks.poll_id FROM webhooks
+                    JOIN polls ON polls.id = webhooks.poll_id
+                    WHERE NOT webhooks.closed_notified AND polls.expires_at <= $1",
+                    &[&now],
+                )
+            })
+            .await?;
+        let mut ids = Vec::with_capacity(rows.len());
+        for row in rows {
+            ids.push(row.try_get("poll_id")?);
+        }
+        Ok(ids)
+    }
+
+    /// Queues the `poll_closed` event for a poll's webhooks, unless it has already been queued
+    pub async fn queue_poll_closed_event(
+        &mut self,
+        poll_id: String,
+        payload: String,
+    ) -> Result<(), ErrorKind> {
+        self.run(move |c| -> Result<(), postgres::Error> {
+            let mut transaction = c.transaction()?;
+            transaction.execute(
+                "INSERT INTO webhook_deliveries (webhook_id, poll_id, url, secret, event, payload)
+                SELECT id, poll_id, url, secret, $2, $3 FROM webhooks
+                WHERE poll_id = $1 AND NOT closed_notified",
+                &[&poll_id, &webhooks::POLL_CLOSED, &payload],
+            )?;
+            transaction.execute(
+                "UPDATE webhooks SET closed_notified = TRUE WHERE poll_id = $1",
+                &[&poll_id],
+            )?;
+            transaction.commit()
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Gets requests to webhooks that are due to be attempted
+    pub async fn get_due_webhook_deliveries(
+        &mut self,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>, ErrorKind> {
+        let now = SystemTime::now();
+        let rows = self
+            .run(move |c| {
+                c.query(
+                    "SELECT * FROM webhook_deliveries WHERE next_attempt_at <= $1
+                    ORDER BY next_attempt_at LIMIT $2",
+                    &[&now, &limit],
+                )
+            })
+            .await?;
+        rows.iter().map(WebhookDelivery::from_row).collect()
+    }
+
+    /// Records an attempt to send a request to a webhook.
+    ///
+    /// `next_attempt_at` is when to try again, or `None` if the request succeeded or won't be retried.
+    pub async fn record_webhook_attempt(
+        &mut self,
+        id: i64,
+        delivered: bool,
+        status: Option<i32>,
+        error: Option<String>,
+        next_attempt_at: Option<SystemTime>,
+    ) -> Result<(), ErrorKind> {
+        let delivered_at = if delivered {
+            Some(SystemTime::now())
+        } else {
+            None
+        };
+        self.run(move |c| {
+            c.execute(
+                "UPDATE webhook_deliveries SET attempts = attempts + 1, delivered_at = $2,
+                last_status = $3, last_error = $4, next_attempt_at = $5
+                WHERE id = $1",
+                &[&id, &delivered_at, &status, &error, &next_attempt_at],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Gets the IDs of polls that have ended, but whose creators haven't been sent the results yet
+    pub async fn get_polls_to_email_results(&mut self) -> Result<Vec<String>, ErrorKind> {
+        let now = SystemTime::now();
+        let rows = self
+            .run(move |c| {
+                c.query(
+                    "SELECT id FROM polls
+                    WHERE creator_email IS NOT NULL AND NOT results_emailed AND expires_at <= $1",
+                    &[&now],
+                )
+            })
+            .await?;
+        let mut ids = Vec::with_capacity(rows.len());
+        for row in rows {
+            ids.push(row.try_get("id")?);
+        }
+        Ok(ids)
+    }
+
+    /// Queues the email with a poll's results, unless it has already been queued
+    pub async fn queue_results_email(
+        &mut self,
+        poll_id: String,
+        email: QueuedEmail,
+    ) -> Result<(), ErrorKind> {
+        self.run(move |c| -> Result<(), postgres::Error> {
+            let mut transaction = c.transaction()?;
+            let updated = transaction.execute(
+                "UPDATE polls SET results_emailed = TRUE WHERE id = $1 AND NOT results_emailed",
+                &[&poll_id],
+            )?;
+            if updated > 0 {
+                transaction.execute(
+                    QUEUE_EMAIL,
+                    &[&poll_id, &email.recipient, &email.subject, &email.body],
+                )?;
+            }
+            transaction.commit()
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Gets emails that are due to be sent
+    pub async fn get_due_emails(&mut self, limit: i64) -> Result<Vec<Email>, ErrorKind> {
+        let now = SystemTime::now();
+        let rows = self
+            .run(move |c| {
+                c.query(
+                    "SELECT id, poll_id, recipient, subject, body, attempts FROM emails
+                    WHERE next_attempt_at <= $1
+                    ORDER BY next_attempt_at LIMIT $2",
+                    &[&now, &limit],
+                )
+            })
+            .await?;
+        let mut emails = Vec::with_capacity(rows.len());
+        for row in rows {
+            emails.push(Email {
+                id: row.try_get("id")?,
+                poll_id: row.try_get("poll_id")?,
+                email: QueuedEmail {
+                    recipient: row.try_get("recipient")?,
+                    subject: row.try_get("subject")?,
+                    body: row.try_get("body")?,
+                },
+                attempts: row.try_get("attempts")?,
+            });
+        }
+        Ok(emails)
+    }
+
+    /// Counts an attempt to send an email before it's made, scheduling the next one, so that a
+    /// failure to record how it went can't get the email sent over and over.
+    ///
+    /// `next_attempt_at` is when to try again if this attempt fails, or `None` if it won't be retried.
+    pub async fn start_email_attempt(
+        &mut self,
+        id: i64,
+        next_attempt_at: Option<SystemTime>,
+    ) -> Result<(), ErrorKind> {
+        self.run(move |c| {
+            c.execute(
+                "UPDATE emails SET attempts = attempts + 1, next_attempt_at = $2 WHERE id = $1",
+                &[&id, &next_attempt_at],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Records how an attempt to send an email went. Once it's been sent, its body is deleted and
+    /// it isn't retried.
+    pub async fn record_email_attempt(
+        &mut self,
+        id: i64,
+        error: Option<String>,
+    ) -> Result<(), ErrorKind> {
+        let sent = error.is_none();
+        self.run(move |c| {
+            c.execute(
+                "UPDATE emails SET last_error = $3,
+                sent_at = CASE WHEN $2 THEN NOW() END,
+                next_attempt_at = CASE WHEN $2 THEN NULL ELSE next_attempt_at END,
+                body = CASE WHEN $2 THEN '' ELSE body END
+                WHERE id = $1",
+                &[&id, &sent, &error],
+            )
+        })
+        .await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,200 @@
+//! The public directory of polls whose creators chose to list them.
+//!
+//! Pages are fetched with keyset pagination: each page ends with a cursor naming the last poll
+//! shown, and the next page starts after it, so polls created in the meantime don't shift pages.
+
+use crate::error::VisibleError;
+
+/// The number of polls on a page if none is requested
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+/// The most polls that can be on a page
+pub const MAX_PAGE_SIZE: i64 = 100;
+/// The longest search query that's accepted
+pub const MAX_QUERY_LENGTH: usize = 256;
+
+/// The order polls are listed in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Sort {
+    /// Most recently created first
+    Newest,
+    /// Least recently created first
+    Oldest,
+    /// Soonest to end first
+    Ending,
+}
+
+impl Sort {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "newest" => Some(Sort::Newest),
+            "oldest" => Some(Sort::Oldest),
+            "ending" => Some(Sort::Ending),
+            _ => None,
+        }
+    }
+
+    /// The column polls are sorted by
+    pub fn column(&self) -> &'static str {
+        match self {
+            Sort::Newest | Sort::Oldest => "created_at",
+            Sort::Ending => "expires_at",
+        }
+    }
+
+    /// Whether polls are sorted with the largest value first
+    pub fn descending(&self) -> bool {
+        matches!(self, Sort::Newest)
+    }
+}
+
+/// Which polls to list, based on whether they've ended
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Status {
+    Active,
+    Ended,
+}
+
+impl Status {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "active" => Some(Status::Active),
+            "ended" => Some(Status::Ended),
+            _ => None,
+        }
+    }
+}
+
+/// Where a page starts: just after the poll with this ID and sort key
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cursor {
+    /// The value of the sort column, in microseconds since the UNIX epoch
+    pub key: i64,
+    pub id: String,
+}
+
+impl Cursor {
+    /// Encodes the cursor so it can be passed back in a URL
+    pub fn encode(&self) -> String {
+        hex::encode(format!("{}.{}", self.key, self.id))
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let decoded = String::from_utf8(hex::decode(cursor).ok()?).ok()?;
+        let (key, id) = decoded.split_once('.')?;
+        Some(Cursor {
+            key: key.parse().ok()?,
+            id: id.to_string(),
+        })
+    }
+}
+
+/// A request for a page of the directory
+#[derive(Debug, PartialEq, Clone)]
+pub struct Query {
+    /// Words to search for in the title and description
+    pub text: Option<String>,
+    pub status: Option<Status>,
+    pub sort: Sort,
+    pub after: Option<Cursor>,
+    pub limit: i64,
+}
+
+impl Query {
+    /// Builds a query from the parameters of a request, checking that they're valid
+    pub fn parse(
+        text: Option<&str>,
+        status: Option<&str>,
+        sort: Option<&str>,
+        cursor: Option<&str>,
+        limit: Option<i64>,
+    ) -> Result<Self, VisibleError> {
+        let invalid = |message: &str| VisibleError::InvalidQuery(message.to_string());
+
+        let text = text.map(str::trim).filter(|text| !text.is_empty());
+        if text.is_some_and(|text| text.len() > MAX_QUERY_LENGTH) {
+            return Err(invalid("The search query must be at most 256 characters."));
+        }
+        let status = match status {
+            Some(name) => Some(
+                Status::from_name(name)
+                    .ok_or_else(|| invalid("The status must be 'active' or 'ended'."))?,
+            ),
+            None => None,
+        };
+        let sort = match sort {
+            Some(name) => Sort::from_name(name)
+                .ok_or_else(|| invalid("The sort must be 'newest', 'oldest', or 'ending'."))?,
+            None => Sort::Newest,
+        };
+        let after = match cursor {
+            Some(cursor) => {
+                Some(Cursor::decode(cursor).ok_or_else(|| invalid("The cursor is not valid."))?)
+            }
+            None => None,
+        };
+        let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            return Err(invalid("The limit must be between 1 and 100."));
+        }
+
+        Ok(Query {
+            text: text.map(str::to_string),
+            status,
+            sort,
+            after,
+            limit,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors() {
+        let cursor = Cursor {
+            key: 1_640_000_000_123_456,
+            id: "a.b-c".to_string(),
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+        assert_eq!(Cursor::decode("not hex"), None);
+        assert_eq!(Cursor::decode(&hex::encode("no separator")), None);
+        assert_eq!(Cursor::decode(&hex::encode("abc.poll")), None);
+    }
+
+    #[test]
+    fn queries() {
+        assert_eq!(
+            Query::parse(None, None, None, None, None),
+            Ok(Query {
+                text: None,
+                status: None,
+                sort: Sort::Newest,
+                after: None,
+                limit: DEFAULT_PAGE_SIZE,
+            })
+        );
+
+        let query = Query::parse(
+            Some(" lunch "),
+            Some("ended"),
+            Some("ending"),
+            None,
+            Some(5),
+        );
+        let query = query.unwrap();
+        assert_eq!(query.text.as_deref(), Some("lunch"));
+        assert_eq!(query.status, Some(Status::Ended));
+        assert_eq!(query.sort, Sort::Ending);
+        assert_eq!(query.limit, 5);
+        let query = Query::parse(Some("   "), None, None, None, None).unwrap();
+        assert_eq!(query.text, None);
+
+        assert!(Query::parse(None, Some("open"), None, None, None).is_err());
+        assert!(Query::parse(None, None, Some("votes"), None, None).is_err());
+        assert!(Query::parse(None, None, None, Some("zz"), None).is_err());
+        assert!(Query::parse(None, None, None, None, Some(0)).is_err());
+        assert!(Query::parse(None, None, None, None, Some(101)).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Emails, sent through the SMTP relay configured in `Rocket.toml`.
+//!
+//! Invitations to invitation-only polls, with each voter's one-time voting link, are queued when
+//! the poll is created, and creators who give an email address are sent the results when their
+//! poll ends. Emails are queued in the `emails` table and sent by a background job, which retries
+//! failures with exponential backoff.
+
+use std::time::{Duration, SystemTime};
+
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use rocket::serde::json::json;
+
+use crate::config::{Config, SmtpConfig, SmtpSecurity};
+use crate::database::postgres::{Email, PostgresConnection, QueuedEmail};
+use crate::error::ErrorKind;
+use crate::html::readable_time;
+use crate::logging::{self, Level};
+use crate::poll::{winner_names, Poll};
+
+/// How many times an email is attempted before giving up
+pub const MAX_ATTEMPTS: i32 = 6;
+/// How long to wait before the first retry; each retry after that waits twice as long
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// How long to wait for the SMTP relay to respond
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// The most emails sent each time the delivery job runs
+const BATCH_SIZE: i64 = 50;
+
+/// The most people that can be invited to a poll
+pub const MAX_INVITEES: usize = 1000;
+/// The longest email address that can be used
+const MAX_ADDRESS_LENGTH: usize = 254;
+
+/// Sends emails through the SMTP relay
+pub type Mailer = AsyncSmtpTransport<Tokio1Executor>;
+
+/// Builds the transport for the configured SMTP relay. Nothing is sent until an email is.
+pub fn mailer(config: &SmtpConfig) -> Result<Mailer, lettre::transport::smtp::Error> {
+    let builder = match config.security {
+        SmtpSecurity::None => Mailer::builder_dangerous(&config.host),
+        SmtpSecurity::StartTls => Mailer::starttls_relay(&config.host)?,
+        SmtpSecurity::Tls => Mailer::relay(&config.host)?,
+    };
+    let mut builder = builder.port(config.port).timeout(Some(TIMEOUT));
+    if let Some(username) = &config.username {
+        builder = builder.credentials(Credentials::new(
+            username.clone(),
+            config.password.clone().unwrap_or_default(),
+        ));
+    }
+    Ok(builder.build())
+}
+
+/// How long to wait before retrying an email that has failed `attempts` times,
+/// or `None` if it shouldn't be retried
+pub fn retry_delay(attempts: i32) -> Option<Duration> {
+    if attempts >= MAX_ATTEMPTS {
+        return None;
+    }
+    Some(FIRST_RETRY_DELAY * 2u32.pow(attempts.max(1) as u32 - 1))
+}
+
+/// Checks that an email address can be used, returning why it can't if not
+pub fn validate_address(address: &str) -> Result<(), String> {
+    if address.len() > MAX_ADDRESS_LENGTH {
+        return Err(format!(
+            "Email addresses can't be longer than {} characters.",
+            MAX_ADDRESS_LENGTH
+        ));
+    }
+    match address.parse::<Address>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("'{}' is not a valid email address.", address)),
+    }
+}
+
+/// The email inviting someone to vote in a poll. `link` is their one-time voting link.
+pub fn invitation(poll: &Poll, recipient: &str, link: &str) -> QueuedEmail {
+    let description = poll.description.trim();
+    let description = if description.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n\n", description)
+    };
+    QueuedEmail {
+        recipient: recipient.to_string(),
+        subject: format!("You're invited to vote in \"{}\"", poll.title),
+        body: format!(
+            "You've been invited to vote in \"{title}\" on BetterPoll.\n\n\
+            {description}\
+            Vote here before {end}:\n\
+            {link}\n\n\
+            This link can only be used once, so please don't share it.\n",
+            title = poll.title,
+            description = description,
+            end = readable_time(poll.end_time),
+            link = link,
+        ),
+    }
+}
+
+/// The email telling a poll's creator who won, or `None` if the poll hasn't ended
+pub fn results(poll: Poll, recipient: &str, poll_url: &str) -> Option<QueuedEmail> {
+    let winners = winner_names(poll.winners?, poll.num_winners);
+    let outcome = match poll.votes.len() {
+        0 => "Nobody voted.".to_string(),
+        num_votes => {
+            let voters = match num_votes {
+                1 => "1 person".to_string(),
+                n => format!("{} people", n),
+            };
+            let label = if winners.len() == 1 {
+                "Winner"
+            } else {
+                "Winners"
+            };
+            let list: String = winners
+                .iter()
+                .enumerate()
+                .map(|(i, winner)| format!("{}. {}\n", i + 1, winner))
+                .collect();
+            format!("{} voted.\n\n{}:\n{}", voters, label, list.trim_end())
+        }
+    };
+    Some(QueuedEmail {
+        recipient: recipient.to_string(),
+        subject: format!("Results of \"{}\"", poll.title),
+        body: format!(
+            "Voting in \"{}\" on BetterPoll has ended. {}\n\n\
+            See the full results at {}\n",
+            poll.title, outcome, poll_url
+        ),
+    })
+}
+
+/// Sends one email, returning an error message if it failed
+async fn send(mailer: &Mailer, from: &Mailbox, email: &Email) -> Option<String> {
+    let to = match email.email.recipient.parse::<Mailbox>() {
+        Ok(to) => to,
+        Err(e) => return Some(e.to_string()),
+    };
+    let message = Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(email.email.subject.as_str())
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.email.body.clone());
+    let message = match message {
+        Ok(message) => message,
+        Err(e) => return Some(e.to_string()),
+    };
+    mailer.send(message).await.err().map(|e| e.to_string())
+}
+
+/// Sends the emails that are due, returning how many were sent successfully
+pub async fn deliver_due(
+    conn: &mut PostgresConnection,
+    mailer: &Mailer,
+    from: &Mailbox,
+) -> Result<usize, ErrorKind> {
+    let mut sent = 0;
+    for email in conn.get_due_emails(BATCH_SIZE).await? {
+        let next_attempt_at =
+            retry_delay(email.attempts + 1).map(|delay| SystemTime::now() + delay);
+        conn.start_email_attempt(email.id, next_attempt_at).await?;
+        let error = send(mailer, from, &email).await;
+        if error.is_none() {
+            sent += 1;
+        }
+        if let (Some(error), None) = (&error, next_attempt_at) {
+            // The recipient isn't logged, since they may have been invited to vote
+            logging::log(
+                Level::Warn,
+                "Giving up on an email.",
+                json!({ "email_id": email.id, "poll_id": email.poll_id, "error": error }),
+            );
+        }
+        conn.record_email_attempt(email.id, error).await?;
+    }
+    Ok(sent)
+}
+
+/// Queues results emails for polls that have ended since this last ran
+pub async fn queue_closed_polls(
+    conn: &mut PostgresConnection,
+    config: &Config,
+) -> Result<(), ErrorKind> {
+    for poll_id in conn.get_polls_to_email_results().await? {
+        let poll = match conn.get_poll_by_id(poll_id.clone()).await? {
+            Some(poll) => poll,
+            None => continue,
+        };
+        let recipient = match &poll.creator_email {
+            Some(recipient) => recipient.clone(),
+            None => continue,
+        };
+        if let Some(email) = results(poll, &recipient, &config.poll_url(&poll_id)) {
+            conn.queue_results_email(poll_id, email).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll() -> Poll {
+        let mut poll = Poll::new(
+            Some("lunch".to_string()),
+            "Lunch".to_string(),
+            "Where should we go?".to_string(),
+            vec!["Pizza".to_string(), "Curry".to_string()],
+            Duration::from_secs(3600),
+            1,
+            false,
+        )
+        .unwrap();
+        poll.end_time = 1_643_630_400;
+        poll
+    }
+
+    #[test]
+    fn backoff() {
+        assert_eq!(retry_delay(1), Some(Duration::from_secs(60)));
+        assert_eq!(retry_delay(2), Some(Duration::from_secs(120)));
+        assert_eq!(
+            retry_delay(MAX_ATTEMPTS - 1),
+            Some(Duration::from_secs(60 * 16))
+        );
+        assert_eq!(retry_delay(MAX_ATTEMPTS), None);
+    }
+
+    #[test]
+    fn addresses() {
+        assert!(validate_address("voter@example.com").is_ok());
+        assert!(validate_address("first.last+polls@mail.example.org").is_ok());
+        assert!(validate_address("not an address").is_err());
+        assert!(validate_address("voter@").is_err());
+        assert!(validate_address("Voter <voter@example.com>").is_err());
+        assert!(validate_address(&format!("{}@example.com", "a".repeat(250))).is_err());
+    }
+
+    #[test]
+    fn invitations() {
+        let email = invitation(
+            &poll(),
+            "voter@example.com",
+            "https://betterpoll.cc/poll/lunch?invite=abc",
+        );
+        assert_eq!(email.recipient, "voter@example.com");
+        assert_eq!(email.subject, "You're invited to vote in \"Lunch\"");
+        assert!(email.body.contains("\n\nWhere should we go?\n\n"));
+        assert!(email.body.contains(
+            "Vote here before 2022-01-31 12:00 UTC:\nhttps://betterpoll.cc/poll/lunch?invite=abc\n"
+        ));
+    }
+
+    #[test]
+    fn results_emails() {
+        let url = "https://betterpoll.cc/poll/lunch";
+        assert_eq!(results(poll(), "creator@example.com", url), None);
+
+        let mut ended = poll();
+        ended.end_time = 0;
+        ended.votes = vec![crate::poll::RankedChoiceVote {
+            ranked_choices: vec!["Curry".to_string()],
+            voter_ip_hash: None,
+        }];
+        ended.finish().unwrap();
+        let email = results(ended, "creator@example.com", url).unwrap();
+        assert_eq!(email.subject, "Results of \"Lunch\"");
+        assert!(email
+            .body
+            .contains("1 person voted.\n\nWinner:\n1. Curry\n\n"));
+        assert!(email
+            .body
+            .ends_with("See the full results at https://betterpoll.cc/poll/lunch\n"));
+    }
+
+    #[test]
+    fn mailers() {
+        let mut config = SmtpConfig {
+            host: "localhost".to_string(),
+            port: 1025,
+            security: SmtpSecurity::None,
+            username: None,
+            password: None,
+            from: "BetterPoll <polls@example.com>".to_string(),
+        };
+        assert!(mailer(&config).is_ok());
+        config.security = SmtpSecurity::StartTls;
+        config.username = Some("polls@example.com".to_string());
+        assert!(mailer(&config).is_ok());
+    }
+}
@@ -0,0 +1,278 @@
+//! Embedding polls in other websites.
+//!
+//! `/poll/<pollid>/embed` is a small, self-contained page showing a poll's status and results,
+//! meant to be put in an iframe, and `/oembed` is an [oEmbed](https://oembed.com/) provider that
+//! gives sites like wikis and blogs the iframe for a link to a poll on the frontend.
+
+use rocket::http::Header;
+use rocket::response::content::Html;
+use rocket::serde::json::{json, Value};
+use rocket::State;
+
+use crate::api::poll_info_json;
+use crate::config::Config;
+use crate::error::{ErrorKind, VisibleError};
+use crate::html::{encode_query_component, escape, readable_time, truncate};
+use crate::metrics::TimedConnection;
+
+/// The size of the iframe, if the consumer doesn't ask for it to be smaller
+const DEFAULT_WIDTH: u32 = 480;
+const DEFAULT_HEIGHT: u32 = 320;
+/// How often an open poll's embed reloads, in seconds
+const REFRESH_INTERVAL: u64 = 30;
+/// The longest description shown in an embed, in characters
+const MAX_DESCRIPTION_LENGTH: usize = 300;
+
+/// Returns the routes that should be made available
+pub fn routes() -> Vec<rocket::Route> {
+    routes![embed, oembed]
+}
+
+/// Finds the ID of the poll that a frontend URL links to
+fn poll_id_from_url<'u>(config: &Config, url: &'u str) -> Option<&'u str> {
+    let prefix = format!("{}/poll/", config.frontend_url.trim_end_matches('/'));
+    let rest = url.strip_prefix(prefix.as_str())?;
+    let id = rest.split(['?', '#']).next()?;
+    let id = id.strip_suffix('/').unwrap_or(id);
+    let is_valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+    if is_valid {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+/// The size of the iframe, within the largest size the consumer allows
+fn dimensions(max_width: Option<u32>, max_height: Option<u32>) -> (u32, u32) {
+    (
+        max_width.map_or(DEFAULT_WIDTH, |max| max.min(DEFAULT_WIDTH)),
+        max_height.map_or(DEFAULT_HEIGHT, |max| max.min(DEFAULT_HEIGHT)),
+    )
+}
+
+/// Renders the embedded page from a poll's info, as returned by `GET /poll/<pollid>`
+fn render(info: &Value, poll_url: &str) -> String {
+    let ended = info["ended"].as_bool().unwrap_or(false);
+    let num_votes = info["numVotes"].as_u64().unwrap_or(0);
+    let ending_time = readable_time(info["endingTime"].as_u64().unwrap_or(0));
+    let votes = match num_votes {
+        1 => "1 vote".to_string(),
+        n => format!("{} votes", n),
+    };
+    let status = if ended {
+        format!("Voting ended {} with {}.", ending_time, votes)
+    } else {
+        format!("Voting closes {}. {} so far.", ending_time, votes)
+    };
+
+    let winners = match info["winners"].as_array() {
+        Some(winners) if num_votes > 0 => {
+            let heading = match (info["provisional"].as_bool(), winners.len()) {
+                (Some(true), _) => "Current standings",
+                (_, 1) => "Winner",
+                _ => "Winners",
+            };
+            let items: String = winners
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|winner| format!("<li>{}</li>", escape(winner)))
+                .collect();
+            format!("<h2>{}</h2>\n<ol>{}</ol>\n", heading, items)
+        }
+        _ => String::new(),
+    };
+    let refresh = if ended {
+        String::new()
+    } else {
+        format!(
+            "<meta http-equiv=\"refresh\" content=\"{}\">\n",
+            REFRESH_INTERVAL
+        )
+    };
+    let link_text = if ended {
+        "See the results on BetterPoll"
+    } else {
+        "Vote on BetterPoll"
+    };
+
+    format!(
+        "<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+<meta charset=\"utf-8\">
+{refresh}<title>{name} | BetterPoll</title>
+<style>
+body {{ font-family: sans-serif; margin: 0; padding: 1em; color: #363636; }}
+h1 {{ font-size: 1.25em; margin: 0 0 0.25em; }}
+h2 {{ font-size: 1em; margin: 0.75em 0 0.25em; }}
+p, ol {{ margin: 0.25em 0; }}
+.status {{ color: #7a7a7a; }}
+a {{ color: #485fc7; }}
+</style>
+</head>
+<body>
+<h1>{name}</h1>
+<p>{description}</p>
+<p class=\"status\">{status}</p>
+{winners}<p><a href=\"{url}\" target=\"_blank\" rel=\"noopener\">{link_text} &rarr;</a></p>
+</body>
+</html>
+",
+        refresh = refresh,
+        name = escape(info["name"].as_str().unwrap_or_default()),
+        description = escape(&truncate(
+            info["description"].as_str().unwrap_or_default(),
+            MAX_DESCRIPTION_LENGTH
+        )),
+        status = status,
+        winners = winners,
+        url = escape(poll_url),
+        link_text = link_text,
+    )
+}
+
+/// A page that any site may put in an iframe
+#[derive(Responder)]
+struct Embeddable {
+    page: Html<String>,
+    content_security_policy: Header<'static>,
+}
+
+#[get("/poll/<pollid>/embed")]
+async fn embed(
+    mut conn: TimedConnection,
+    config: &State<Config>,
+    pollid: String,
+) -> Result<Embeddable, ErrorKind> {
+    let poll = match conn.get_poll_by_id(pollid.clone()).await? {
+        Some(poll) => poll,
+        None => return Err(VisibleError::PollNotFound(pollid).into()),
+    };
+    let info = poll_info_json(poll, false)?;
+    Ok(Embeddable {
+        page: Html(render(&info, &config.poll_url(&pollid))),
+        // Browsers ignore the `X-Frame-Options` header when `frame-ancestors` is set
+        content_security_policy: Header::new(
+            "Content-Security-Policy",
+            "default-src 'none'; style-src 'unsafe-inline'; frame-ancestors *",
+        ),
+    })
+}
+
+#[get("/oembed?<url>&<maxwidth>&<maxheight>&<format>")]
+async fn oembed(
+    mut conn: TimedConnection,
+    config: &State<Config>,
+    url: &str,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+    format: Option<&str>,
+) -> Result<Value, ErrorKind> {
+    if !matches!(format, Some("json") | None) {
+        return Err(VisibleError::EmbedFormatNotSupported.into());
+    }
+    let pollid = poll_id_from_url(config, url).ok_or(VisibleError::UnknownEmbedUrl)?;
+    let poll = match conn.get_poll_by_id(pollid.to_string()).await? {
+        Some(poll) => poll,
+        None => return Err(VisibleError::UnknownEmbedUrl.into()),
+    };
+
+    let (width, height) = dimensions(maxwidth, maxheight);
+    let src = format!(
+        "{}/poll/{}/embed",
+        config.api_url.trim_end_matches('/'),
+        pollid
+    );
+    let html = format!(
+        "<iframe src=\"{}\" width=\"{}\" height=\"{}\" style=\"border: 0\" title=\"{}\" loading=\"lazy\"></iframe>",
+        escape(&src),
+        width,
+        height,
+        escape(&poll.title)
+    );
+    Ok(json!({
+        "version": "1.0",
+        "type": "rich",
+        "provider_name": "BetterPoll",
+        "provider_url": format!("{}/", config.frontend_url.trim_end_matches('/')),
+        "title": poll.title,
+        "html": html,
+        "width": width,
+        "height": height,
+        "cache_age": REFRESH_INTERVAL,
+    }))
+}
+
+/// Where consumers can find the oEmbed response for a poll, for oEmbed discovery
+pub fn oembed_url(config: &Config, poll_id: &str) -> String {
+    format!(
+        "{}/oembed?url={}&format=json",
+        config.api_url.trim_end_matches('/'),
+        encode_query_component(&config.poll_url(poll_id))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        rocket::Config::figment()
+            .merge(("frontend_url", "https://polls.example/"))
+            .extract()
+            .unwrap()
+    }
+
+    #[test]
+    fn poll_urls() {
+        let config = config();
+        let id = |url: &'static str| poll_id_from_url(&config, url);
+        assert_eq!(id("https://polls.example/poll/abc"), Some("abc"));
+        assert_eq!(id("https://polls.example/poll/a.b-c_d/"), Some("a.b-c_d"));
+        assert_eq!(id("https://polls.example/poll/abc?x=1#y"), Some("abc"));
+        assert_eq!(id("https://polls.example/poll/"), None);
+        assert_eq!(id("https://polls.example/poll/a/b"), None);
+        assert_eq!(id("https://elsewhere.example/poll/abc"), None);
+        assert_eq!(id("https://polls.example/new"), None);
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(dimensions(None, None), (DEFAULT_WIDTH, DEFAULT_HEIGHT));
+        assert_eq!(dimensions(Some(300), Some(1000)), (300, DEFAULT_HEIGHT));
+    }
+
+    #[test]
+    fn rendering() {
+        let open = json!({
+            "name": "Lunch <today>",
+            "description": "Where?",
+            "endingTime": 1_643_630_400u64,
+            "numVotes": 1,
+            "ended": false,
+        });
+        let page = render(&open, "https://polls.example/poll/lunch");
+        assert!(page.contains("<h1>Lunch &lt;today&gt;</h1>"));
+        assert!(page.contains("Voting closes 2022-01-31 12:00 UTC. 1 vote so far."));
+        assert!(page.contains("http-equiv=\"refresh\""));
+        assert!(page.contains("href=\"https://polls.example/poll/lunch\""));
+        assert!(!page.contains("<ol>"));
+
+        let ended = json!({
+            "name": "Lunch",
+            "description": "",
+            "endingTime": 1_643_630_400u64,
+            "numVotes": 3,
+            "ended": true,
+            "winners": ["Pizza", "Curry"],
+            "provisional": false,
+        });
+        let page = render(&ended, "https://polls.example/poll/lunch");
+        assert!(page.contains("Voting ended 2022-01-31 12:00 UTC with 3 votes."));
+        assert!(page.contains("<h2>Winners</h2>\n<ol><li>Pizza</li><li>Curry</li></ol>"));
+        assert!(!page.contains("http-equiv=\"refresh\""));
+    }
+}
@@ -4,9 +4,19 @@
 
 use std::{fmt::Display, num::TryFromIntError};
 
+use rocket::data::ByteUnit;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::json;
+
+use crate::logging::{self, Level, RequestId};
+use crate::metrics;
+
 #[derive(Debug)]
 pub enum ErrorKind {
     Internal(InternalError),
+    Visible(VisibleError),
 }
 
 impl<T> From<T> for ErrorKind
@@ -18,14 +28,201 @@ where
     }
 }
 
-#[derive(Debug)]
+impl From<VisibleError> for ErrorKind {
+    fn from(err: VisibleError) -> Self {
+        ErrorKind::Visible(err)
+    }
+}
+
+/// Responds with JSON of the form `{"success": false, "error": <message>, "code": <code>, "requestId": <id>}`
+/// and the error's HTTP status. Internal errors are logged, and their details aren't shown.
+impl<'r> Responder<'r, 'static> for ErrorKind {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let (status, body) = match self {
+            ErrorKind::Internal(e) => {
+                metrics::INTERNAL_ERRORS
+                    .with_label_values(&[e.variant_name()])
+                    .inc();
+                let mut fields = logging::request_fields(request);
+                fields["error"] = json!(format!("{:?}", e));
+                fields["backtrace"] = json!(format!("{:?}", backtrace::Backtrace::new()));
+                logging::log(Level::Error, "internal error", fields);
+                (
+                    Status::InternalServerError,
+                    json!({
+                        "success": false,
+                        "error": "Sorry, an internal server error occured. If you report this problem, please include the request ID.",
+                        "code": "internal_error",
+                        "requestId": RequestId::of(request).0,
+                    }),
+                )
+            }
+            ErrorKind::Visible(e) => (
+                e.status(),
+                json!({
+                    "success": false,
+                    "error": e.to_string(),
+                    "code": e.code(),
+                    "requestId": RequestId::of(request).0,
+                }),
+            ),
+        };
+        Response::build_from(body.respond_to(request)?)
+            .status(status)
+            .ok()
+    }
+}
+
+/// Errors caused by the request, which are explained to the user
+#[derive(Debug, PartialEq)]
 pub enum VisibleError {
-    // Reserved for future errors
+    /// There is no poll with the given ID
+    PollNotFound(String),
+    /// The poll has ended, so ballots can't be cast or changed
+    PollEnded,
+    /// Something (described by the message) isn't available until the poll has ended
+    PollNotEnded(&'static str),
+    /// The client's IP address has already voted in a poll with IP protection
+    AlreadyVoted,
+    /// There is no ballot with the given token in the poll
+    BallotNotFound,
+    /// The ballot's choices aren't valid for the poll
+    InvalidBallot(String),
+    /// The parameters for a new poll aren't valid
+    InvalidPoll(String),
+    /// A poll already exists with the requested ID
+    PollIdTaken(String),
+    /// The client's IP address couldn't be determined
+    NoClientIp,
+    /// The proof-of-work solution is missing or wrong
+    InvalidProofOfWork(&'static str),
+    /// An uploaded BLT file couldn't be read
+    InvalidBlt(String),
+    /// An uploaded BLT file is too big
+    BltTooLarge(ByteUnit),
+    /// The requested export format doesn't exist
+    InvalidFormat,
+    /// No database connection is available
+    DatabaseUnavailable,
+    /// Only the poll's creator can do this, and the admin token is missing or wrong
+    AdminTokenRequired,
+    /// A webhook can't be registered, for the reason given
+    InvalidWebhook(String),
+    /// A request's query parameters aren't valid, for the reason given
+    InvalidQuery(String),
+    /// An oEmbed request's URL isn't a link to a poll
+    UnknownEmbedUrl,
+    /// An oEmbed response was requested in a format other than JSON
+    EmbedFormatNotSupported,
+    /// The invitation to an invitation-only poll is missing, wrong, or already used
+    InvalidInvitation,
+}
+
+impl VisibleError {
+    /// A stable, machine-readable name for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            VisibleError::PollNotFound(_) => "poll_not_found",
+            VisibleError::PollEnded => "poll_ended",
+            VisibleError::PollNotEnded(_) => "poll_not_ended",
+            VisibleError::AlreadyVoted => "already_voted",
+            VisibleError::BallotNotFound => "ballot_not_found",
+            VisibleError::InvalidBallot(_) => "invalid_ballot",
+            VisibleError::InvalidPoll(_) => "invalid_poll",
+            VisibleError::PollIdTaken(_) => "poll_id_taken",
+            VisibleError::NoClientIp => "no_client_ip",
+            VisibleError::InvalidProofOfWork(_) => "invalid_proof_of_work",
+            VisibleError::InvalidBlt(_) => "invalid_blt",
+            VisibleError::BltTooLarge(_) => "blt_too_large",
+            VisibleError::InvalidFormat => "invalid_format",
+            VisibleError::DatabaseUnavailable => "database_unavailable",
+            VisibleError::AdminTokenRequired => "admin_token_required",
+            VisibleError::InvalidWebhook(_) => "invalid_webhook",
+            VisibleError::InvalidQuery(_) => "invalid_query",
+            VisibleError::UnknownEmbedUrl => "unknown_embed_url",
+            VisibleError::EmbedFormatNotSupported => "embed_format_not_supported",
+            VisibleError::InvalidInvitation => "invalid_invitation",
+        }
+    }
+
+    /// The HTTP status to respond with
+    pub fn status(&self) -> Status {
+        match self {
+            VisibleError::PollNotFound(_)
+            | VisibleError::BallotNotFound
+            | VisibleError::UnknownEmbedUrl => Status::NotFound,
+            VisibleError::PollEnded => Status::Gone,
+            VisibleError::PollNotEnded(_)
+            | VisibleError::AlreadyVoted
+            | VisibleError::PollIdTaken(_) => Status::Conflict,
+            VisibleError::BltTooLarge(_) => Status::PayloadTooLarge,
+            VisibleError::DatabaseUnavailable => Status::ServiceUnavailable,
+            VisibleError::AdminTokenRequired | VisibleError::InvalidInvitation => Status::Forbidden,
+            VisibleError::EmbedFormatNotSupported => Status::NotImplemented,
+            VisibleError::InvalidBallot(_)
+            | VisibleError::InvalidPoll(_)
+            | VisibleError::NoClientIp
+            | VisibleError::InvalidProofOfWork(_)
+            | VisibleError::InvalidBlt(_)
+            | VisibleError::InvalidFormat
+            | VisibleError::InvalidWebhook(_)
+            | VisibleError::InvalidQuery(_) => Status::BadRequest,
+        }
+    }
 }
 
 impl Display for VisibleError {
-    fn fmt(&self, _: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        unreachable!("this should never happen");
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VisibleError::PollNotFound(id) => write!(f, "No poll was found with the ID '{}'.", id),
+            VisibleError::PollEnded => write!(
+                f,
+                "This poll has ended, so ballots can no longer be cast or changed."
+            ),
+            VisibleError::PollNotEnded(message) | VisibleError::InvalidProofOfWork(message) => {
+                write!(f, "{}", message)
+            }
+            VisibleError::AlreadyVoted => write!(f, "You have already voted in this poll."),
+            VisibleError::BallotNotFound => {
+                write!(f, "No ballot was found with that token in this poll.")
+            }
+            VisibleError::InvalidBallot(message) | VisibleError::InvalidPoll(message) => {
+                write!(f, "{}", message)
+            }
+            VisibleError::PollIdTaken(id) => {
+                write!(f, "A poll already exists with the URL '{}'.", id)
+            }
+            VisibleError::NoClientIp => {
+                write!(f, "No IP address could be determined from the request.")
+            }
+            VisibleError::InvalidBlt(message) => {
+                write!(f, "The BLT file could not be read: {}", message)
+            }
+            VisibleError::BltTooLarge(max) => write!(f, "BLT files must be smaller than {}.", max),
+            VisibleError::InvalidFormat => {
+                write!(f, "The format must be 'csv', 'json', or 'blt'.")
+            }
+            VisibleError::DatabaseUnavailable => {
+                write!(f, "The database is unavailable. Please try again later.")
+            }
+            VisibleError::AdminTokenRequired => write!(
+                f,
+                "Only the poll's creator can do this; send the poll's admin token in the X-Admin-Token header."
+            ),
+            VisibleError::InvalidWebhook(message) | VisibleError::InvalidQuery(message) => {
+                write!(f, "{}", message)
+            }
+            VisibleError::UnknownEmbedUrl => {
+                write!(f, "The URL is not a link to a BetterPoll poll.")
+            }
+            VisibleError::EmbedFormatNotSupported => {
+                write!(f, "Only the 'json' format is supported.")
+            }
+            VisibleError::InvalidInvitation => write!(
+                f,
+                "This poll is invitation-only; use the voting link from your invitation, which can only be used once."
+            ),
+        }
     }
 }
 
@@ -33,11 +230,38 @@ impl Display for VisibleError {
 pub enum InternalError {
     Database(postgres::Error),
     UnknownVotingMethodDiscriminant(i32),
+    UnknownProvisionalResultsDiscriminant(i32),
     InvalidNumWinners(i32, TryFromIntError),
     TallyStick(tallystick::TallyError),
     CouldNotConvertDBTimeToUNIX(std::time::SystemTimeError, String),
     InvalidCreationTime(String, u64),
     InvalidEndTime(String, u64),
+    Csv(csv::Error),
+    Utf8(std::string::FromUtf8Error),
+    QrCode(qrcode::types::QrError),
+    Image(image::ImageError),
+}
+
+impl InternalError {
+    /// The name of the variant, used to label metrics
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            InternalError::Database(_) => "Database",
+            InternalError::UnknownVotingMethodDiscriminant(_) => "UnknownVotingMethodDiscriminant",
+            InternalError::UnknownProvisionalResultsDiscriminant(_) => {
+                "UnknownProvisionalResultsDiscriminant"
+            }
+            InternalError::InvalidNumWinners(_, _) => "InvalidNumWinners",
+            InternalError::TallyStick(_) => "TallyStick",
+            InternalError::CouldNotConvertDBTimeToUNIX(_, _) => "CouldNotConvertDBTimeToUNIX",
+            InternalError::InvalidCreationTime(_, _) => "InvalidCreationTime",
+            InternalError::InvalidEndTime(_, _) => "InvalidEndTime",
+            InternalError::Csv(_) => "Csv",
+            InternalError::Utf8(_) => "Utf8",
+            InternalError::QrCode(_) => "QrCode",
+            InternalError::Image(_) => "Image",
+        }
+    }
 }
 
 impl From<postgres::Error> for InternalError {
@@ -51,3 +275,27 @@ impl From<tallystick::TallyError> for InternalError {
         InternalError::TallyStick(err)
     }
 }
+
+impl From<csv::Error> for InternalError {
+    fn from(err: csv::Error) -> Self {
+        InternalError::Csv(err)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for InternalError {
+    fn from(err: std::string::FromUtf8Error) -> Self {
+        InternalError::Utf8(err)
+    }
+}
+
+impl From<qrcode::types::QrError> for InternalError {
+    fn from(err: qrcode::types::QrError) -> Self {
+        InternalError::QrCode(err)
+    }
+}
+
+impl From<image::ImageError> for InternalError {
+    fn from(err: image::ImageError) -> Self {
+        InternalError::Image(err)
+    }
+}
@@ -0,0 +1,46 @@
+//! Live updates about polls, which are streamed to clients as Server-Sent Events
+
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::tokio::sync::broadcast;
+use rocket::{Orbit, Rocket};
+
+/// How many changes can be queued for a slow subscriber before it starts missing some
+const CAPACITY: usize = 1024;
+
+/// Tells event streams when the ballots in a poll change
+pub struct PollEvents {
+    sender: broadcast::Sender<String>,
+}
+
+impl Default for PollEvents {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+}
+
+impl PollEvents {
+    /// Announces that a ballot in the poll has been cast, changed or retracted
+    pub fn ballots_changed(&self, pollid: &str) {
+        // Sending only fails when nobody is listening
+        let _ = self.sender.send(pollid.to_string());
+    }
+
+    /// Receives the IDs of polls whose ballots change from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.sender.subscribe()
+    }
+}
+
+/// The running server, which event streams use to get database connections when they need them
+/// rather than holding one for as long as the client is connected
+pub struct Server<'r>(pub &'r Rocket<Orbit>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Server<'r> {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Server(request.rocket()))
+    }
+}
@@ -0,0 +1,86 @@
+//! Exporting ballots in formats other programs can read, and reading them back
+
+use crate::error::ErrorKind;
+use crate::poll::Poll;
+
+/// Formats ballots as CSV.
+///
+/// There is one row per ballot, with the 1st choice in the first column, and so on.
+/// Rows are padded with empty cells so that every row has one column per candidate.
+pub fn ballots_to_csv(poll: &Poll, ballots: &[Vec<String>]) -> Result<String, ErrorKind> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+
+    let header = (1..=poll.candidates.len()).map(|n| format!("Choice {}", n));
+    writer.write_record(header)?;
+    for ballot in ballots {
+        let padding = poll.candidates.len().saturating_sub(ballot.len());
+        let row = ballot
+            .iter()
+            .map(String::as_str)
+            .chain(std::iter::repeat_n("", padding));
+        writer.write_record(row)?;
+    }
+
+    let bytes = writer
+        .into_inner()
+        .map_err(|e| csv::Error::from(e.into_error()))?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Reads ballots in the format written by [`ballots_to_csv`].
+///
+/// The first row is a header and is skipped. Empty cells are ignored, so rows can have any length.
+pub fn ballots_from_csv(input: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+
+    let mut ballots = vec![];
+    for record in reader.records() {
+        let record = record.map_err(|e| e.to_string())?;
+        let ballot: Vec<String> = record
+            .iter()
+            .map(str::trim)
+            .filter(|choice| !choice.is_empty())
+            .map(String::from)
+            .collect();
+        if !ballot.is_empty() {
+            ballots.push(ballot);
+        }
+    }
+    Ok(ballots)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn csv() {
+        let poll = Poll::new(
+            None,
+            "".to_string(),
+            "".to_string(),
+            vec!["A".to_string(), "B, Jr.".to_string(), "C".to_string()],
+            Duration::from_secs(1),
+            1,
+            false,
+        )
+        .unwrap();
+        let ballots = vec![
+            vec!["B, Jr.".to_string(), "A".to_string()],
+            vec!["C".to_string(), "A".to_string(), "B, Jr.".to_string()],
+        ];
+
+        assert_eq!(
+            ballots_to_csv(&poll, &ballots).unwrap(),
+            "Choice 1,Choice 2,Choice 3\n\"B, Jr.\",A,\nC,A,\"B, Jr.\"\n"
+        );
+        assert_eq!(
+            ballots_from_csv(&ballots_to_csv(&poll, &ballots).unwrap()).unwrap(),
+            ballots
+        );
+    }
+}
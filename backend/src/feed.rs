@@ -0,0 +1,134 @@
+//! An Atom feed of listed polls, announcing new ones and the results of those that have ended.
+
+use rocket::http::ContentType;
+use rocket::State;
+
+use crate::config::Config;
+use crate::error::ErrorKind;
+use crate::html::{escape, rfc3339};
+use crate::metrics::TimedConnection;
+use crate::poll::{winner_names, Poll};
+
+/// The most entries of each kind (new polls and results) in the feed
+const FEED_LENGTH: i64 = 20;
+
+/// Returns the routes that should be made available
+pub fn routes() -> Vec<rocket::Route> {
+    routes![feed]
+}
+
+/// An entry in the feed
+struct Entry {
+    id: String,
+    title: String,
+    link: String,
+    updated: u64,
+    summary: String,
+}
+
+impl Entry {
+    /// Announces a new poll
+    fn created(config: &Config, poll: &Poll) -> Self {
+        let link = config.poll_url(&poll.id);
+        Entry {
+            id: link.clone(),
+            title: format!("New poll: {}", poll.title),
+            link,
+            updated: poll.creation_time,
+            summary: poll.description.clone(),
+        }
+    }
+
+    /// Announces the results of a poll that has ended
+    fn closed(config: &Config, poll: Poll) -> Self {
+        let link = config.poll_url(&poll.id);
+        let summary = match poll.winners {
+            Some(winners) if !poll.votes.is_empty() => {
+                let winners = winner_names(winners, poll.num_winners);
+                let label = if winners.len() == 1 {
+                    "Winner"
+                } else {
+                    "Winners"
+                };
+                let votes = match poll.votes.len() {
+                    1 => "1 vote".to_string(),
+                    n => format!("{} votes", n),
+                };
+                format!("{}: {} ({})", label, winners.join(", "), votes)
+            }
+            _ => "Nobody voted in this poll.".to_string(),
+        };
+        Entry {
+            id: format!("{}#results", link),
+            title: format!("Poll closed: {}", poll.title),
+            link,
+            updated: poll.end_time,
+            summary,
+        }
+    }
+
+    fn to_xml(&self) -> String {
+        format!(
+            "  <entry>
+    <id>{}</id>
+    <title>{}</title>
+    <link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>
+    <updated>{}</updated>
+    <summary>{}</summary>
+  </entry>
+",
+            escape(&self.id),
+            escape(&self.title),
+            escape(&self.link),
+            rfc3339(self.updated),
+            escape(&self.summary)
+        )
+    }
+}
+
+/// Builds the feed, with the newest entries first
+fn atom(config: &Config, mut entries: Vec<Entry>) -> String {
+    entries.sort_by(|a, b| b.updated.cmp(&a.updated).then_with(|| a.id.cmp(&b.id)));
+    let home = format!("{}/", config.frontend_url.trim_end_matches('/'));
+    let updated = entries.first().map_or(0, |entry| entry.updated);
+
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<feed xmlns=\"http://www.w3.org/2005/Atom\">
+  <id>{home}</id>
+  <title>BetterPoll</title>
+  <subtitle>New polls and results</subtitle>
+  <link rel=\"alternate\" type=\"text/html\" href=\"{home}\"/>
+  <updated>{updated}</updated>
+  <author><name>BetterPoll</name></author>
+",
+        home = escape(&home),
+        updated = rfc3339(updated)
+    );
+    for entry in &entries {
+        xml.push_str(&entry.to_xml());
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+#[get("/feed.atom")]
+async fn feed(
+    mut conn: TimedConnection,
+    config: &State<Config>,
+) -> Result<(ContentType, String), ErrorKind> {
+    let mut entries: Vec<Entry> = conn
+        .get_recent_listed_polls(false, FEED_LENGTH)
+        .await?
+        .iter()
+        .map(|poll| Entry::created(config, poll))
+        .collect();
+    for poll in conn.get_recent_listed_polls(true, FEED_LENGTH).await? {
+        entries.push(Entry::closed(config, poll));
+    }
+
+    Ok((
+        ContentType::new("application", "atom+xml"),
+        atom(config, entries),
+    ))
+}
@@ -0,0 +1,64 @@
+//! Liveness and readiness checks, for orchestrators and load balancers
+
+use std::time::Instant;
+
+use rocket::http::Status;
+use rocket::serde::json::{json, Value};
+use rocket::State;
+
+use crate::database::postgres::SCHEMA_VERSION;
+use crate::metrics::TimedConnection;
+
+/// When the server started, so that its uptime can be reported
+pub struct StartTime(pub Instant);
+
+impl Default for StartTime {
+    fn default() -> Self {
+        StartTime(Instant::now())
+    }
+}
+
+/// Returns the routes that should be made available
+pub fn routes() -> Vec<rocket::Route> {
+    routes![live, ready]
+}
+
+/// Information about the running server, included in every health check
+fn server_info(start_time: &StartTime) -> Value {
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime": start_time.0.elapsed().as_secs(),
+    })
+}
+
+#[get("/health/live")]
+fn live(start_time: &State<StartTime>) -> Value {
+    let mut result = server_info(start_time);
+    result["success"] = json!(true);
+    result
+}
+
+#[get("/health/ready")]
+async fn ready(start_time: &State<StartTime>, conn: Option<TimedConnection>) -> (Status, Value) {
+    let (database, schema_version) = match conn {
+        Some(mut conn) => match conn.get_schema_version().await {
+            Ok(version) => ("ok", version),
+            Err(_) => ("error", None),
+        },
+        None => ("unreachable", None),
+    };
+    let is_ready = schema_version == Some(SCHEMA_VERSION);
+
+    let mut result = server_info(start_time);
+    result["success"] = json!(is_ready);
+    result["database"] = json!(database);
+    result["schemaVersion"] = json!(schema_version);
+    result["expectedSchemaVersion"] = json!(SCHEMA_VERSION);
+
+    let status = if is_ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (status, result)
+}
@@ -0,0 +1,121 @@
+//! Helpers for the HTML and XML documents that the backend generates.
+
+/// Escapes text for use in HTML or XML, including inside attribute values
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encodes text for use in a URL's query string
+pub fn encode_query_component(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Shortens text to at most `max_chars` characters, ending it with an ellipsis if it was cut
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    let text = text.trim();
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.truncate(truncated.trim_end().len());
+    truncated.push('…');
+    truncated
+}
+
+/// Formats a UNIX timestamp (in seconds) as an RFC 3339 date, like `2022-01-31T12:00:00Z`
+pub fn rfc3339(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64;
+    let seconds = timestamp % 86_400;
+
+    // Converts days since 1970-01-01 to a date in the proleptic Gregorian calendar;
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// Formats a UNIX timestamp (in seconds) for people to read, like `2022-01-31 12:00 UTC`
+pub fn readable_time(timestamp: u64) -> String {
+    let time = rfc3339(timestamp);
+    format!("{} {} UTC", &time[..10], &time[11..16])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        assert_eq!(
+            escape("<b>Fish & \"chips\"</b> it's"),
+            "&lt;b&gt;Fish &amp; &quot;chips&quot;&lt;/b&gt; it&apos;s"
+        );
+    }
+
+    #[test]
+    fn query_encoding() {
+        assert_eq!(
+            encode_query_component("https://betterpoll.cc/poll/a_b.c-d~"),
+            "https%3A%2F%2Fbetterpoll.cc%2Fpoll%2Fa_b.c-d~"
+        );
+        assert_eq!(encode_query_component("é &"), "%C3%A9%20%26");
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339(1_643_630_400), "2022-01-31T12:00:00Z");
+        assert_eq!(rfc3339(1_672_531_199), "2022-12-31T23:59:59Z");
+        assert_eq!(readable_time(1_643_630_400), "2022-01-31 12:00 UTC");
+    }
+
+    #[test]
+    fn truncation() {
+        assert_eq!(truncate("  short  ", 10), "short");
+        assert_eq!(truncate("exactly ten", 11), "exactly ten");
+        assert_eq!(truncate("one two three", 9), "one two…");
+        assert_eq!(truncate("ééééé", 3), "éé…");
+    }
+}
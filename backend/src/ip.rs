@@ -0,0 +1,237 @@
+//! Working out which IP address a request came from
+
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use rocket::request::{FromRequest, Outcome, Request};
+
+use crate::config::Config;
+
+/// The IP address of the client that made a request.
+///
+/// If the request came through one of the `trusted_proxies`, this is taken from the
+/// `Forwarded` or `X-Forwarded-For` header; otherwise, it's the address of the connection.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let remote = match request.remote() {
+            Some(addr) => addr.ip(),
+            None => return Outcome::Forward(()),
+        };
+        let trusted_proxies = match request.rocket().state::<Config>() {
+            Some(config) => &config.trusted_proxies[..],
+            None => &[],
+        };
+
+        let forwarded: Vec<&str> = request.headers().get("Forwarded").collect();
+        let x_forwarded_for: Vec<&str> = request.headers().get("X-Forwarded-For").collect();
+        let forwarded_for = if !forwarded.is_empty() {
+            parse_forwarded(&forwarded.join(","))
+        } else {
+            parse_x_forwarded_for(&x_forwarded_for.join(","))
+        };
+
+        Outcome::Success(ClientIp(client_ip(remote, &forwarded_for, trusted_proxies)))
+    }
+}
+
+/// Finds the client's IP address, given the address the request came from and the addresses
+/// listed in its forwarding headers (in the order they appear, so the original client is first).
+///
+/// Addresses are checked from the nearest hop outwards, and the first one that isn't a trusted proxy
+/// is the client. `None` represents an address in the header that couldn't be parsed; since it can't
+/// be checked, the hop before it is used.
+pub fn client_ip(
+    remote: IpAddr,
+    forwarded_for: &[Option<IpAddr>],
+    trusted_proxies: &[IpNet],
+) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    let mut client = remote;
+    for hop in forwarded_for.iter().rev() {
+        if !is_trusted(&client) {
+            break;
+        }
+        match hop {
+            Some(ip) => client = *ip,
+            None => break,
+        }
+    }
+    client
+}
+
+/// Returns the first address in the network of the given size that `ip` belongs to.
+///
+/// A single client often controls a whole IPv6 /64 (or more), so this is used to treat every
+/// address in that range as the same voter. IPv4-mapped IPv6 addresses are treated as IPv4.
+pub fn network_prefix(ip: IpAddr, ipv4_prefix_length: u8, ipv6_prefix_length: u8) -> IpAddr {
+    let ip = match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        IpAddr::V4(_) => ip,
+    };
+
+    match ip {
+        IpAddr::V4(v4) => IpAddr::V4(
+            Ipv4Net::new(v4, ipv4_prefix_length.min(32))
+                .expect("prefix length should be valid")
+                .network(),
+        ),
+        IpAddr::V6(v6) => IpAddr::V6(
+            Ipv6Net::new(v6, ipv6_prefix_length.min(128))
+                .expect("prefix length should be valid")
+                .network(),
+        ),
+    }
+}
+
+/// Parses an address from a forwarding header, which may be quoted, in brackets, or have a port
+fn parse_forwarded_address(address: &str) -> Option<IpAddr> {
+    let address = address.trim().trim_matches('"');
+    if let Ok(ip) = address.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Some(bracketed) = address.strip_prefix('[') {
+        // IPv6 address in brackets, possibly followed by a port
+        let end = bracketed.find(']')?;
+        return bracketed[..end].parse().ok();
+    }
+    address.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+/// Gets the `for` addresses from the value of a `Forwarded` header (RFC 7239)
+fn parse_forwarded(header: &str) -> Vec<Option<IpAddr>> {
+    header
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("for") {
+                    Some(parse_forwarded_address(value))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+/// Gets the addresses from the value of an `X-Forwarded-For` header
+fn parse_x_forwarded_for(header: &str) -> Vec<Option<IpAddr>> {
+    header
+        .split(',')
+        .filter(|address| !address.trim().is_empty())
+        .map(parse_forwarded_address)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn proxies() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()]
+    }
+
+    #[test]
+    fn untrusted_remote() {
+        // Headers from untrusted clients are ignored, since they could be forged
+        assert_eq!(
+            client_ip(ip("192.0.2.1"), &[Some(ip("198.51.100.1"))], &proxies()),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn trusted_proxy() {
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &[Some(ip("198.51.100.1"))], &proxies()),
+            ip("198.51.100.1")
+        );
+        assert_eq!(client_ip(ip("10.0.0.1"), &[], &proxies()), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn chained_proxies() {
+        // The client can put anything at the start of the header, so only the
+        // address added by our nearest untrusted hop counts
+        assert_eq!(
+            client_ip(
+                ip("::1"),
+                &[
+                    Some(ip("192.0.2.99")),
+                    Some(ip("198.51.100.1")),
+                    Some(ip("10.1.2.3"))
+                ],
+                &proxies()
+            ),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn unparseable_hop() {
+        assert_eq!(
+            client_ip(
+                ip("10.0.0.1"),
+                &[Some(ip("198.51.100.1")), None],
+                &proxies()
+            ),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn network_prefixes() {
+        assert_eq!(network_prefix(ip("192.0.2.77"), 32, 64), ip("192.0.2.77"));
+        assert_eq!(network_prefix(ip("192.0.2.77"), 24, 64), ip("192.0.2.0"));
+        assert_eq!(
+            network_prefix(ip("2001:db8:1:2:aaaa:bbbb:cccc:dddd"), 32, 64),
+            ip("2001:db8:1:2::")
+        );
+        assert_eq!(
+            network_prefix(ip("2001:db8:1:2:aaaa:bbbb:cccc:dddd"), 32, 128),
+            ip("2001:db8:1:2:aaaa:bbbb:cccc:dddd")
+        );
+        assert_eq!(
+            network_prefix(ip("::ffff:192.0.2.77"), 24, 64),
+            ip("192.0.2.0")
+        );
+        // Out-of-range lengths are treated as the whole address
+        assert_eq!(network_prefix(ip("192.0.2.77"), 200, 200), ip("192.0.2.77"));
+    }
+
+    #[test]
+    fn parse_headers() {
+        assert_eq!(
+            parse_x_forwarded_for("198.51.100.1, 10.0.0.2,2001:db8::1"),
+            vec![
+                Some(ip("198.51.100.1")),
+                Some(ip("10.0.0.2")),
+                Some(ip("2001:db8::1"))
+            ]
+        );
+        assert_eq!(
+            parse_forwarded(
+                "for=192.0.2.60;proto=http;by=203.0.113.43, For=\"[2001:db8:cafe::17]:4711\", for=unknown"
+            ),
+            vec![Some(ip("192.0.2.60")), Some(ip("2001:db8:cafe::17")), None]
+        );
+        assert_eq!(
+            parse_forwarded("proto=https;for=192.0.2.60:8080"),
+            vec![Some(ip("192.0.2.60"))]
+        );
+    }
+}
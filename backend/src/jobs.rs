@@ -0,0 +1,221 @@
+//! Background jobs that run periodically while the server is up
+
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::serde::json::{json, Value};
+use rocket::{Build, Orbit, Rocket, Shutdown};
+
+use crate::config::Config;
+use crate::database::postgres::{ConnectionSource, PostgresConnection};
+use crate::email;
+use crate::logging::{self, Level};
+use crate::ratelimit::RateLimiter;
+use crate::webhooks;
+
+/// How often the jobs are run
+const JOB_INTERVAL: Duration = Duration::from_secs(60);
+/// How often webhook requests that are due are sent
+const WEBHOOK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often emails that are due are sent
+const EMAIL_INTERVAL: Duration = Duration::from_secs(10);
+/// How often rate limit buckets that have refilled are forgotten
+const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Fairing that starts the background jobs once the server launches. Each one first runs an
+/// interval after launch.
+///
+/// Each job takes a database connection when it runs, rather than holding one the whole time.
+/// The jobs stop when the server shuts down, or when it's dropped without shutting down, as
+/// local clients in tests are.
+pub struct BackgroundJobs;
+
+/// Where the jobs get their connections. Only the server holds this, so that the jobs don't keep
+/// the connection pool alive after it's gone.
+struct JobConnections(Arc<ConnectionSource>);
+
+#[rocket::async_trait]
+impl Fairing for BackgroundJobs {
+    fn info(&self) -> Info {
+        Info {
+            name: "Background jobs",
+            kind: Kind::Ignite | Kind::Liftoff,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        match ConnectionSource::new(&rocket).await {
+            Some(source) => Ok(rocket.manage(JobConnections(Arc::new(source)))),
+            None => Ok(rocket),
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let shutdown = rocket.shutdown();
+
+        // This doesn't need the database, so it runs even if the other jobs can't
+        if let Some(limiter) = rocket.state::<RateLimiter>().cloned() {
+            let shutdown = shutdown.clone();
+            rocket::tokio::spawn(async move {
+                while wait(RATE_LIMIT_INTERVAL, &shutdown).await {
+                    limiter.prune(Instant::now());
+                }
+            });
+        }
+
+        let config = match rocket.state::<Config>() {
+            Some(config) => config.clone(),
+            None => return,
+        };
+        let source = match rocket.state::<JobConnections>() {
+            Some(JobConnections(source)) => Arc::downgrade(source),
+            None => {
+                logging::log(
+                    Level::Error,
+                    "Could not find the database; background jobs won't run.",
+                    Value::Null,
+                );
+                return;
+            }
+        };
+
+        let smtp = config.smtp.clone();
+        let allow_private_webhooks = config.allow_private_webhooks;
+        {
+            let (source, shutdown) = (source.clone(), shutdown.clone());
+            rocket::tokio::spawn(async move {
+                while let Some(source) = next_run(JOB_INTERVAL, &shutdown, &source).await {
+                    if let Some(mut conn) = connect(&source).await {
+                        run_jobs(&mut conn, &config).await;
+                    }
+                }
+            });
+        }
+
+        // Webhooks are sent separately, so that slow receivers don't hold up the other jobs
+        {
+            let (source, shutdown) = (source.clone(), shutdown.clone());
+            rocket::tokio::spawn(async move {
+                while let Some(source) = next_run(WEBHOOK_INTERVAL, &shutdown, &source).await {
+                    let mut conn = match connect(&source).await {
+                        Some(conn) => conn,
+                        None => continue,
+                    };
+                    if let Err(e) = webhooks::deliver_due(&mut conn, allow_private_webhooks).await {
+                        logging::log(
+                            Level::Error,
+                            "Could not deliver webhooks.",
+                            json!({ "error": format!("{:?}", e) }),
+                        );
+                    }
+                }
+            });
+        }
+
+        // So are emails, if there's an SMTP relay to send them through
+        if let Some(smtp) = smtp {
+            let mailer = match email::mailer(&smtp) {
+                Ok(mailer) => mailer,
+                Err(e) => {
+                    logging::log(
+                        Level::Error,
+                        "Could not set up the SMTP relay; emails won't be sent.",
+                        json!({ "error": e.to_string() }),
+                    );
+                    return;
+                }
+            };
+            let from = match smtp.from.parse() {
+                Ok(from) => from,
+                Err(_) => {
+                    logging::log(
+                        Level::Error,
+                        "The SMTP 'from' mailbox in Rocket.toml isn't valid; emails won't be sent.",
+                        json!({ "from": smtp.from }),
+                    );
+                    return;
+                }
+            };
+            rocket::tokio::spawn(async move {
+                while let Some(source) = next_run(EMAIL_INTERVAL, &shutdown, &source).await {
+                    let mut conn = match connect(&source).await {
+                        Some(conn) => conn,
+                        None => continue,
+                    };
+                    if let Err(e) = email::deliver_due(&mut conn, &mailer, &from).await {
+                        logging::log(
+                            Level::Error,
+                            "Could not send emails.",
+                            json!({ "error": format!("{:?}", e) }),
+                        );
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Waits for `interval`, returning `false` if the server starts shutting down first
+async fn wait(interval: Duration, shutdown: &Shutdown) -> bool {
+    rocket::tokio::select! {
+        _ = shutdown.clone() => false,
+        _ = rocket::tokio::time::sleep(interval) => true,
+    }
+}
+
+/// Waits for the next run of a job that uses the database, returning where to get a connection,
+/// or `None` if the job should stop because the server is shutting down or has been dropped
+async fn next_run(
+    interval: Duration,
+    shutdown: &Shutdown,
+    source: &Weak<ConnectionSource>,
+) -> Option<Arc<ConnectionSource>> {
+    if wait(interval, shutdown).await {
+        source.upgrade()
+    } else {
+        None
+    }
+}
+
+/// Takes a connection for a job, logging an error if none is free
+async fn connect(source: &ConnectionSource) -> Option<PostgresConnection> {
+    let conn = source.get().await;
+    if conn.is_none() {
+        logging::log(
+            Level::Error,
+            "Could not get a database connection for a background job.",
+            Value::Null,
+        );
+    }
+    conn
+}
+
+async fn run_jobs(conn: &mut PostgresConnection, config: &Config) {
+    // Voter IP hashes are only needed to prevent double voting, so they're deleted once a poll ends
+    if let Err(e) = conn.clear_ended_poll_ip_hashes().await {
+        logging::log(
+            Level::Error,
+            "Could not clear IP hashes of ended polls.",
+            json!({ "error": format!("{:?}", e) }),
+        );
+    }
+
+    if let Err(e) = webhooks::queue_closed_polls(conn).await {
+        logging::log(
+            Level::Error,
+            "Could not queue webhooks for polls that have ended.",
+            json!({ "error": format!("{:?}", e) }),
+        );
+    }
+
+    if config.smtp.is_some() {
+        if let Err(e) = email::queue_closed_polls(conn, config).await {
+            logging::log(
+                Level::Error,
+                "Could not queue results emails for polls that have ended.",
+                json!({ "error": format!("{:?}", e) }),
+            );
+        }
+    }
+}
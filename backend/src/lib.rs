@@ -0,0 +1,113 @@
+#![feature(proc_macro_hygiene, decl_macro)]
+
+use database::postgres::PostgresConnection;
+use rocket::{
+    http::Method,
+    request::Request,
+    response::content::Html,
+    serde::json::{json, Value},
+    Build, Rocket,
+};
+
+#[macro_use]
+extern crate rocket;
+#[macro_use]
+extern crate rocket_sync_db_pools;
+
+#[cfg(not(fuzzing))]
+mod api;
+#[cfg(fuzzing)]
+pub mod api;
+pub mod blt;
+mod calendar;
+pub mod config;
+mod crypto;
+pub mod database;
+mod directory;
+mod email;
+mod embed;
+pub mod error;
+mod events;
+pub mod export;
+mod feed;
+mod health;
+mod html;
+mod ip;
+mod jobs;
+mod logging;
+mod metrics;
+pub mod poll;
+mod pow;
+mod preview;
+mod qr;
+mod ratelimit;
+mod webhooks;
+
+#[catch(404)]
+fn not_found() -> Html<String> {
+    Html(String::from(
+        "You have reached the backend API for BetterPoll, a ranked-choice voting website. <br />
+        It is currently in development; see <a href='https://github.com/AnnikaCodes/betterpoll'>the GitHub repository</a> for more information and API documentation."
+    ))
+}
+
+#[catch(422)]
+fn bad_json(request: &Request) -> Value {
+    json!({
+        "success": false,
+        "error": "You must provide valid JSON with all required fields for this endpoint specified. \
+        Refer to the API documentation at https://github.com/AnnikaCodes/betterpoll#api for more information.",
+        "code": "invalid_json",
+        "requestId": logging::RequestId::of(request).0,
+    })
+}
+
+#[catch(429)]
+fn too_many_requests(request: &Request) -> Value {
+    json!({
+        "success": false,
+        "error": "You are making too many requests. Please wait a while and try again.",
+        "code": "rate_limited",
+        "requestId": logging::RequestId::of(request).0,
+    })
+}
+
+/// Builds the server
+pub fn rocket() -> Rocket<Build> {
+    dotenv::dotenv().ok();
+    let cors_regex = std::env::var("ALLOWED_ORIGINS")
+        .expect("The environment variable ALLOWED_ORIGINS must be set to a regular expression defining allowed origins for API access.");
+
+    let cors = rocket_cors::CorsOptions {
+        allowed_origins: rocket_cors::AllowedOrigins::some_regex(&[cors_regex]),
+        allowed_methods: vec![Method::Get, Method::Post]
+            .into_iter()
+            .map(From::from)
+            .collect(),
+        expose_headers: ["X-Request-Id".to_string()].into_iter().collect(),
+        ..Default::default()
+    }
+    .to_cors()
+    .unwrap();
+
+    rocket::build()
+        .register("/", catchers![not_found, bad_json, too_many_requests])
+        .attach(logging::RequestLogger)
+        .attach(metrics::RequestMetrics)
+        .attach(PostgresConnection::fairing())
+        .attach(config::fairing())
+        .attach(jobs::BackgroundJobs)
+        .manage(ratelimit::RateLimiter::default())
+        .manage(pow::SpentChallenges::default())
+        .manage(events::PollEvents::default())
+        .manage(health::StartTime::default())
+        .attach(cors)
+        .mount("/", api::routes())
+        .mount("/", metrics::routes())
+        .mount("/", health::routes())
+        .mount("/", feed::routes())
+        .mount("/", preview::routes())
+        .mount("/", embed::routes())
+        .mount("/", calendar::routes())
+        .mount("/", qr::routes())
+}
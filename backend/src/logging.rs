@@ -0,0 +1,148 @@
+//! Structured logging and request tracing.
+//!
+//! Every log entry is a single line of JSON written to stderr, so that log pipelines can parse
+//! and alert on it. Each request is given an ID, which is logged with everything that happens
+//! while handling it and sent back in the `X-Request-Id` header.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::Request;
+use rocket::serde::json::{json, Value};
+use rocket::{Data, Response};
+
+/// How serious a log entry is
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+/// Builds a log entry. The properties of `fields`, which should be a JSON object, are added to it.
+fn entry(level: Level, message: &str, timestamp: f64, fields: Value) -> Value {
+    let mut entry = match fields {
+        Value::Object(fields) => fields,
+        _ => Default::default(),
+    };
+    entry.insert("timestamp".to_string(), json!(timestamp));
+    entry.insert("level".to_string(), json!(level.as_str()));
+    entry.insert("message".to_string(), json!(message));
+    Value::Object(entry)
+}
+
+/// Writes a log entry to stderr
+pub fn log(level: Level, message: &str, fields: Value) {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or(0.0);
+    eprintln!("{}", entry(level, message, timestamp, fields));
+}
+
+/// The unique ID of a request
+#[derive(Debug, PartialEq, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Gets the ID of a request, giving it one if it doesn't have one yet
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r RequestId {
+        request.local_cache(|| RequestId(format!("{:016x}", rand::random::<u64>())))
+    }
+}
+
+/// Details of a request that are included in log entries about it
+pub fn request_fields(request: &Request<'_>) -> Value {
+    // Every route for a specific poll starts with `/poll/<pollid>`
+    let poll_id = match request.routed_segment(0) {
+        Some("poll") => request.routed_segment(1),
+        _ => None,
+    };
+    json!({
+        "request_id": RequestId::of(request).0,
+        "method": request.method().as_str(),
+        "path": request.uri().path().to_string(),
+        "route": request.route().and_then(|route| route.name.as_deref()),
+        "poll_id": poll_id,
+    })
+}
+
+/// When a request was received, so that its duration can be logged
+struct RequestStart(Option<Instant>);
+
+/// How long it's been since the request was received, if that's known
+pub fn request_duration(request: &Request<'_>) -> Option<Duration> {
+    match request.local_cache(|| RequestStart(None)) {
+        RequestStart(Some(start)) => Some(start.elapsed()),
+        RequestStart(None) => None,
+    }
+}
+
+/// Fairing that gives each request an ID, returns it in the `X-Request-Id` header,
+/// and logs every response
+pub struct RequestLogger;
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request logger",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+        RequestId::of(request);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let request_id = RequestId::of(request);
+        response.set_header(Header::new("X-Request-Id", request_id.0.clone()));
+
+        let mut fields = request_fields(request);
+        fields["status"] = json!(response.status().code);
+        if let Some(duration) = request_duration(request) {
+            fields["duration_ms"] = json!(duration.as_secs_f64() * 1000.0);
+        }
+        log(Level::Info, "request", fields);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries() {
+        assert_eq!(
+            entry(
+                Level::Error,
+                "something broke",
+                1.5,
+                json!({ "poll_id": "abc", "level": "ignored" })
+            ),
+            json!({
+                "timestamp": 1.5,
+                "level": "error",
+                "message": "something broke",
+                "poll_id": "abc",
+            })
+        );
+        assert_eq!(
+            entry(Level::Info, "hello", 0.0, Value::Null),
+            json!({ "timestamp": 0.0, "level": "info", "message": "hello" })
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Prometheus metrics, served at `/metrics`
+
+use std::ops::{Deref, DerefMut};
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, TextEncoder,
+};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::Response;
+
+use crate::database::postgres::PostgresConnection;
+use crate::logging;
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "betterpoll_http_requests_total",
+        "HTTP requests handled, by route, method, and status",
+        &["route", "method", "status"]
+    )
+    .expect("metric should be valid");
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "betterpoll_http_request_duration_seconds",
+        "Time taken to handle HTTP requests, by route",
+        &["route"]
+    )
+    .expect("metric should be valid");
+    pub static ref VOTES_CAST: IntCounter =
+        register_int_counter!("betterpoll_votes_cast_total", "Ballots cast")
+            .expect("metric should be valid");
+    pub static ref POLLS_CREATED: IntCounter =
+        register_int_counter!("betterpoll_polls_created_total", "Polls created")
+            .expect("metric should be valid");
+    pub static ref TALLY_DURATION: HistogramVec = register_histogram_vec!(
+        "betterpoll_tally_duration_seconds",
+        "Time taken to tally a poll's votes, by voting method",
+        &["method"]
+    )
+    .expect("metric should be valid");
+    pub static ref DB_POOL_WAIT: Histogram = register_histogram!(
+        "betterpoll_db_pool_wait_seconds",
+        "Time spent waiting for a database connection from the pool"
+    )
+    .expect("metric should be valid");
+    pub static ref INTERNAL_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "betterpoll_internal_errors_total",
+        "Internal errors returned to clients, by kind",
+        &["variant"]
+    )
+    .expect("metric should be valid");
+}
+
+/// Returns the routes that should be made available
+pub fn routes() -> Vec<rocket::Route> {
+    routes![metrics]
+}
+
+#[get("/metrics")]
+fn metrics() -> (ContentType, String) {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("metrics should be encodable");
+    (
+        ContentType::Plain,
+        String::from_utf8(buffer).expect("metrics should be UTF-8"),
+    )
+}
+
+/// A database connection, which records how long it took to get it from the pool.
+///
+/// It can be used in the same ways as a [`PostgresConnection`].
+pub struct TimedConnection(PostgresConnection);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TimedConnection {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let start = Instant::now();
+        let outcome = request.guard::<PostgresConnection>().await;
+        DB_POOL_WAIT.observe(start.elapsed().as_secs_f64());
+        outcome.map(TimedConnection)
+    }
+}
+
+impl Deref for TimedConnection {
+    type Target = PostgresConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for TimedConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Fairing that counts and times every request
+pub struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        // Requests that didn't match a route are grouped together, so that
+        // clients can't create a new label for every path they request
+        let route = request
+            .route()
+            .and_then(|route| route.name.as_deref())
+            .unwrap_or("unmatched");
+
+        HTTP_REQUESTS
+            .with_label_values(&[
+                route,
+                request.method().as_str(),
+                &response.status().code.to_string(),
+            ])
+            .inc();
+        if let Some(duration) = logging::request_duration(request) {
+            HTTP_REQUEST_DURATION
+                .with_label_values(&[route])
+                .observe(duration.as_secs_f64());
+        }
+    }
+}
@@ -5,17 +5,92 @@ use std::time::SystemTime;
 
 use std::time::Duration;
 
+use rand::seq::SliceRandom;
 use tallystick::schulze::SchulzeTally;
 use tallystick::schulze::Variant;
 use tallystick::RankedCandidate;
 
+use crate::crypto::{constant_time_eq, keyed_hash};
 use crate::error::ErrorKind;
+use crate::metrics;
+
+/// The most candidates that a poll can have
+pub const MAX_CANDIDATES: usize = 1024;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct RankedChoiceVote {
     /// idx 0 is 1st choice, etc
     pub ranked_choices: Vec<String>,
-    pub voter_ip: IpAddr,
+    /// Keyed hash of the voter's IP address, from [`RankedChoiceVote::hash_ip`].
+    ///
+    /// `None` for ballots that weren't cast through the API (for example, ones imported from a file),
+    /// and for all ballots once the poll has ended.
+    pub voter_ip_hash: Option<String>,
+}
+
+impl RankedChoiceVote {
+    /// Hashes a voter's IP address so that it can be used to detect double voting without being stored.
+    ///
+    /// Pass the network prefix from [`crate::ip::network_prefix`] to treat a whole network as one voter.
+    pub fn hash_ip(secret: &str, ip: IpAddr) -> String {
+        keyed_hash(secret, &[b"voter_ip", ip.to_string().as_bytes()])
+    }
+}
+
+/// Gets the names of the winners from a ranking, in order.
+///
+/// Candidates tied for the last winning place are all included, so there may be more than `num_winners`.
+pub fn winner_names(mut ranking: Vec<RankedCandidate<String>>, num_winners: usize) -> Vec<String> {
+    // Sort in reverse order - lowest ranks first
+    ranking.sort_by_key(|candidate| std::cmp::Reverse(candidate.rank));
+    let mut names = vec![];
+    let mut cur_rank = 0;
+    while let Some(candidate) = ranking.pop() {
+        if candidate.rank != cur_rank {
+            if names.len() < num_winners {
+                // take the next rank
+                cur_rank += 1;
+            } else {
+                // We have enough winners!
+                break;
+            }
+        }
+        names.push(candidate.candidate);
+    }
+    names
+}
+
+/// Generates a random secret token, such as the one that lets a voter change their ballot
+pub fn new_secret_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Hashes the token from an invitation to an invitation-only poll, so the token needn't be stored
+pub fn hash_invitation(secret: &str, poll_id: &str, token: &str) -> String {
+    keyed_hash(
+        secret,
+        &[b"invitation", poll_id.as_bytes(), token.as_bytes()],
+    )
+}
+
+/// A hash that lets a voter check that their ballot was counted, without revealing it
+#[derive(Debug, PartialEq, Clone)]
+pub struct BallotReceipt {
+    pub hash: String,
+    /// Random value hashed along with the ballot, so that identical ballots get different receipts
+    pub nonce: String,
+}
+
+impl BallotReceipt {
+    pub fn new(secret: &str, poll_id: &str, ranked_choices: &[String]) -> Self {
+        let nonce = format!("{:032x}", rand::random::<u128>());
+
+        let mut parts: Vec<&[u8]> = vec![b"receipt", poll_id.as_bytes(), nonce.as_bytes()];
+        parts.extend(ranked_choices.iter().map(|choice| choice.as_bytes()));
+        let hash = keyed_hash(secret, &parts);
+
+        Self { hash, nonce }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -23,6 +98,43 @@ pub enum VotingMethod {
     Schulze,
 }
 
+impl VotingMethod {
+    /// The method's name, used to label metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            VotingMethod::Schulze => "schulze",
+        }
+    }
+}
+
+/// Who can see who's winning a poll before it ends
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ProvisionalResults {
+    Hidden,
+    VisibleToAdmin,
+    Public,
+}
+
+impl ProvisionalResults {
+    /// The name used for this setting in the API
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProvisionalResults::Hidden => "hidden",
+            ProvisionalResults::VisibleToAdmin => "visible_to_admin",
+            ProvisionalResults::Public => "public",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hidden" => Some(ProvisionalResults::Hidden),
+            "visible_to_admin" => Some(ProvisionalResults::VisibleToAdmin),
+            "public" => Some(ProvisionalResults::Public),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Poll {
     pub id: String,
@@ -38,6 +150,15 @@ pub struct Poll {
     pub winners: Option<Vec<RankedCandidate<String>>>,
     pub method: VotingMethod,
     pub prohibit_double_vote_by_ip: bool,
+    /// Lets the poll's creator see things that other users can't
+    pub admin_token: String,
+    pub provisional_results: ProvisionalResults,
+    /// Whether the poll is shown in the public directory
+    pub listed: bool,
+    /// Whether only people with an emailed invitation can vote
+    pub invite_only: bool,
+    /// Where to email the results when the poll ends
+    pub creator_email: Option<String>,
 }
 
 impl Poll {
@@ -69,11 +190,57 @@ impl Poll {
             winners: None,
             method: VotingMethod::Schulze,
             prohibit_double_vote_by_ip,
+            admin_token: new_secret_token(),
+            provisional_results: ProvisionalResults::Hidden,
+            listed: false,
+            invite_only: false,
+            creator_email: None,
         })
     }
 
+    /// Returns true if `token` is this poll's admin token
+    pub fn is_admin(&self, token: Option<&str>) -> bool {
+        match token {
+            Some(token) => constant_time_eq(token, &self.admin_token),
+            None => false,
+        }
+    }
+
+    /// Returns every ballot's ranked choices in a random order, without any information about the voters
+    pub fn anonymised_ballots(&self) -> Vec<Vec<String>> {
+        let mut ballots: Vec<Vec<String>> = self
+            .votes
+            .iter()
+            .map(|vote| vote.ranked_choices.clone())
+            .collect();
+        ballots.shuffle(&mut rand::thread_rng());
+        ballots
+    }
+
+    /// Returns true if the current standings can be shown while the poll is open.
+    /// `is_admin` is whether the viewer has the poll's admin token.
+    pub fn shows_provisional_results(&self, is_admin: bool) -> bool {
+        match self.provisional_results {
+            ProvisionalResults::Hidden => false,
+            ProvisionalResults::VisibleToAdmin => is_admin,
+            ProvisionalResults::Public => true,
+        }
+    }
+
+    /// Returns true if the poll is no longer accepting votes, which it stops doing at `end_time`
+    pub fn has_ended(&self) -> bool {
+        self.end_time
+            <= SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("can't find out how long it was since the UNIX epoch")
+                .as_secs()
+    }
+
     /// Finds the winners
     pub fn find_winners(&self) -> Result<Vec<RankedCandidate<String>>, ErrorKind> {
+        let _timer = metrics::TALLY_DURATION
+            .with_label_values(&[self.method.name()])
+            .start_timer();
         let winners = match self.method {
             VotingMethod::Schulze => {
                 let mut tally =
@@ -93,6 +260,77 @@ impl Poll {
         Ok(winners)
     }
 
+    /// Ranks every candidate, not just the winners
+    pub fn full_ranking(&self) -> Result<Vec<RankedCandidate<String>>, ErrorKind> {
+        let mut poll = self.clone();
+        poll.num_winners = self.candidates.len();
+        poll.find_winners()
+    }
+
+    /// Counts how many ballots rank each candidate above each other candidate.
+    ///
+    /// `preferences[i][j]` is the number of ballots preferring `candidates[i]` to `candidates[j]`.
+    /// A candidate who is ranked is preferred to one who isn't.
+    pub fn pairwise_preferences(&self) -> Vec<Vec<u64>> {
+        let n = self.candidates.len();
+        let mut preferences = vec![vec![0; n]; n];
+        for vote in &self.votes {
+            let positions: Vec<Option<usize>> = self
+                .candidates
+                .iter()
+                .map(|candidate| vote.ranked_choices.iter().position(|c| c == candidate))
+                .collect();
+            for (i, row) in preferences.iter_mut().enumerate() {
+                for (j, count) in row.iter_mut().enumerate() {
+                    let prefers = match (positions[i], positions[j]) {
+                        (Some(a), Some(b)) => a < b,
+                        (Some(_), None) => true,
+                        (None, _) => false,
+                    };
+                    if prefers {
+                        *count += 1;
+                    }
+                }
+            }
+        }
+        preferences
+    }
+
+    /// Finds the strength of the strongest path from each candidate to each other candidate,
+    /// which is how the Schulze method (with winning votes) compares them.
+    pub fn schulze_path_strengths(&self) -> Vec<Vec<u64>> {
+        let preferences = self.pairwise_preferences();
+        let n = self.candidates.len();
+        let mut strengths: Vec<Vec<u64>> = (0..n)
+            .map(|i| {
+                (0..n)
+                    .map(|j| {
+                        if preferences[i][j] > preferences[j][i] {
+                            preferences[i][j]
+                        } else {
+                            0
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        for k in 0..n {
+            let from_k = strengths[k].clone();
+            for (i, row) in strengths.iter_mut().enumerate() {
+                if i == k {
+                    continue;
+                }
+                let to_k = row[k];
+                for (j, strength) in row.iter_mut().enumerate() {
+                    if j != i && j != k {
+                        *strength = (*strength).max(to_k.min(from_k[j]));
+                    }
+                }
+            }
+        }
+        strengths
+    }
+
     pub fn finish(&mut self) -> Result<(), ErrorKind> {
         let winners = self.find_winners()?;
         self.winners = Some(winners);
@@ -148,6 +386,84 @@ mod tests {
         assert_eq!(poll.id, id);
     }
 
+    #[test]
+    fn secret_tokens_are_unique() {
+        let token1 = new_secret_token();
+        let token2 = new_secret_token();
+
+        assert_eq!(token1.len(), 32);
+        assert_ne!(token1, token2);
+    }
+
+    #[test]
+    fn receipts_differ_for_identical_ballots() {
+        let choices = vec!["a".to_string(), "b".to_string()];
+        let receipt1 = BallotReceipt::new("secret", "poll", &choices);
+        let receipt2 = BallotReceipt::new("secret", "poll", &choices);
+
+        assert_ne!(receipt1.nonce, receipt2.nonce);
+        assert_ne!(receipt1.hash, receipt2.hash);
+    }
+
+    #[test]
+    fn admin_token() {
+        let poll = Poll::new(
+            None,
+            "".to_string(),
+            "".to_string(),
+            vec![],
+            Duration::from_secs(1),
+            1,
+            false,
+        )
+        .unwrap();
+
+        assert!(poll.is_admin(Some(&poll.admin_token)));
+        assert!(!poll.is_admin(Some("not the token")));
+        assert!(!poll.is_admin(None));
+    }
+
+    #[test]
+    fn ip_hashes() {
+        let ip1 = "127.0.0.1".parse().unwrap();
+        let ip2 = "2001:db8::1".parse().unwrap();
+
+        assert_eq!(
+            RankedChoiceVote::hash_ip("secret", ip1),
+            RankedChoiceVote::hash_ip("secret", ip1)
+        );
+        assert_ne!(
+            RankedChoiceVote::hash_ip("secret", ip1),
+            RankedChoiceVote::hash_ip("secret", ip2)
+        );
+        assert_ne!(
+            RankedChoiceVote::hash_ip("secret", ip1),
+            RankedChoiceVote::hash_ip("other secret", ip1)
+        );
+        assert!(!RankedChoiceVote::hash_ip("secret", ip1).contains("127.0.0.1"));
+    }
+
+    #[test]
+    fn has_ended() {
+        let mut poll = Poll::new(
+            None,
+            "".to_string(),
+            "".to_string(),
+            vec![],
+            Duration::from_secs(1000),
+            1,
+            false,
+        )
+        .unwrap();
+        assert!(!poll.has_ended());
+
+        poll.end_time = poll.creation_time;
+        assert!(poll.has_ended());
+
+        poll.end_time = poll.creation_time - 1;
+        assert!(poll.has_ended());
+    }
+
     #[test]
     fn winners() {
         let a = String::from("a");
@@ -166,27 +482,89 @@ mod tests {
         .unwrap();
         poll.votes.push(RankedChoiceVote {
             ranked_choices: vec![c.clone(), a.clone(), b.clone()],
-            voter_ip: "127.0.0.1".parse().unwrap(),
+            voter_ip_hash: Some("127.0.0.1".to_string()),
         });
         poll.votes.push(RankedChoiceVote {
             ranked_choices: vec![a.clone(), c.clone(), b.clone()],
-            voter_ip: "127.0.0.2".parse().unwrap(),
+            voter_ip_hash: Some("127.0.0.2".to_string()),
         });
         poll.votes.push(RankedChoiceVote {
             ranked_choices: vec![a, c.clone()],
-            voter_ip: "127.0.0.3".parse().unwrap(),
+            voter_ip_hash: Some("127.0.0.3".to_string()),
         });
         poll.votes.push(RankedChoiceVote {
             ranked_choices: vec![b.clone(), c.clone()],
-            voter_ip: "127.0.0.3".parse().unwrap(),
+            voter_ip_hash: Some("127.0.0.3".to_string()),
         });
         poll.votes.push(RankedChoiceVote {
             ranked_choices: vec![b, c.clone()],
-            voter_ip: "127.0.0.3".parse().unwrap(),
+            voter_ip_hash: Some("127.0.0.3".to_string()),
         });
 
         poll.finish().unwrap();
 
         assert_eq!(poll.winners.unwrap()[0].candidate, c);
     }
+
+    #[test]
+    fn schulze_details() {
+        let candidates = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let mut poll = Poll::new(
+            None,
+            "".to_string(),
+            "".to_string(),
+            candidates.clone(),
+            Duration::from_secs(1),
+            1,
+            false,
+        )
+        .unwrap();
+        for ballot in [
+            &candidates[..],
+            &candidates[..],
+            &candidates[1..],
+            &candidates[2..],
+        ] {
+            poll.votes.push(RankedChoiceVote {
+                ranked_choices: ballot.to_vec(),
+                voter_ip_hash: None,
+            });
+        }
+
+        assert_eq!(
+            poll.pairwise_preferences(),
+            vec![vec![0, 2, 2], vec![1, 0, 3], vec![2, 1, 0]]
+        );
+        assert_eq!(
+            poll.schulze_path_strengths(),
+            vec![vec![0, 2, 2], vec![0, 0, 3], vec![0, 0, 0]]
+        );
+
+        let ranking = poll.full_ranking().unwrap();
+        assert_eq!(ranking.len(), 3);
+        assert_eq!(ranking[0].candidate, "a");
+    }
+
+    #[test]
+    fn provisional_results() {
+        let mut poll = Poll::new(
+            None,
+            "".to_string(),
+            "".to_string(),
+            vec![],
+            Duration::from_secs(1),
+            1,
+            false,
+        )
+        .unwrap();
+        assert!(!poll.shows_provisional_results(true));
+
+        poll.provisional_results = ProvisionalResults::VisibleToAdmin;
+        assert!(poll.shows_provisional_results(true));
+        assert!(!poll.shows_provisional_results(false));
+
+        poll.provisional_results = ProvisionalResults::from_name("public").unwrap();
+        assert!(poll.shows_provisional_results(false));
+        assert_eq!(ProvisionalResults::from_name("everyone"), None);
+    }
 }
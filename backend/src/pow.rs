@@ -0,0 +1,187 @@
+//! Hashcash-style proof-of-work challenges, used to slow down bots without a CAPTCHA service.
+//!
+//! A challenge is `<expiry>.<nonce>.<signature>`, where the signature is a keyed hash of the expiry,
+//! the nonce, and what the challenge is for (a poll ID, or `create`), so the server doesn't need to
+//! remember the challenges it hands out. To solve a challenge, the client finds a string `solution`
+//! such that the SHA-256 hash of `<challenge>:<solution>` starts with at least `pow_difficulty` zero bits.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use sha2::{Digest, Sha256};
+
+use crate::crypto::{constant_time_eq, keyed_hash};
+
+/// How long a challenge can be used for after it's issued, in seconds
+pub const CHALLENGE_LIFETIME: u64 = 10 * 60;
+
+/// The scope used for challenges when creating polls
+pub const CREATE_SCOPE: &str = "create";
+
+fn sign(secret: &str, scope: &str, expiry: &str, nonce: &str) -> String {
+    keyed_hash(
+        secret,
+        &[
+            b"challenge",
+            scope.as_bytes(),
+            expiry.as_bytes(),
+            nonce.as_bytes(),
+        ],
+    )
+}
+
+/// Creates a new challenge for `scope`, where `now` is the current UNIX timestamp
+pub fn new_challenge(secret: &str, scope: &str, now: u64) -> String {
+    let expiry = (now + CHALLENGE_LIFETIME).to_string();
+    let nonce = format!("{:032x}", rand::random::<u128>());
+    let signature = sign(secret, scope, &expiry, &nonce);
+    format!("{}.{}.{}", expiry, nonce, signature)
+}
+
+/// Counts the zero bits at the start of a hash
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// Counts the zero bits at the start of the hash of a solution to a challenge
+fn solution_zero_bits(challenge: &str, solution: &str) -> u32 {
+    leading_zero_bits(&Sha256::digest(
+        format!("{}:{}", challenge, solution).as_bytes(),
+    ))
+}
+
+/// Checks a solution to a challenge for `scope`, returning the challenge's expiry time if it's valid
+/// and an error message explaining the problem otherwise.
+///
+/// This doesn't check whether the challenge has already been used; see [`SpentChallenges`].
+pub fn verify(
+    secret: &str,
+    scope: &str,
+    challenge: &str,
+    solution: &str,
+    difficulty: u8,
+    now: u64,
+) -> Result<u64, &'static str> {
+    let parts: Vec<&str> = challenge.split('.').collect();
+    let (expiry, nonce, signature) = match parts[..] {
+        [expiry, nonce, signature] => (expiry, nonce, signature),
+        _ => return Err("The proof-of-work challenge is invalid."),
+    };
+    if !constant_time_eq(signature, &sign(secret, scope, expiry, nonce)) {
+        return Err("The proof-of-work challenge is invalid.");
+    }
+    let expiry: u64 = match expiry.parse() {
+        Ok(expiry) => expiry,
+        Err(_) => return Err("The proof-of-work challenge is invalid."),
+    };
+    if expiry <= now {
+        return Err("The proof-of-work challenge has expired; please try again.");
+    }
+
+    if solution_zero_bits(challenge, solution) < difficulty as u32 {
+        return Err("The proof-of-work solution is incorrect.");
+    }
+    Ok(expiry)
+}
+
+/// Challenges that have already been used, so that each solution only works once
+#[derive(Debug, Default)]
+pub struct SpentChallenges {
+    /// Maps challenges to their expiry times
+    challenges: Mutex<HashMap<String, u64>>,
+}
+
+impl SpentChallenges {
+    /// Marks a challenge as used, returning `false` if it already was
+    pub fn spend(&self, challenge: &str, expiry: u64, now: u64) -> bool {
+        let mut challenges = self
+            .challenges
+            .lock()
+            .expect("spent challenge lock was poisoned");
+        // Expired challenges are rejected anyway, so there's no need to remember them
+        challenges.retain(|_, expiry| *expiry > now);
+        challenges.insert(challenge.to_string(), expiry).is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "secret";
+    const NOW: u64 = 1_000_000;
+
+    /// Finds a solution by brute force, like a client would
+    fn solve(challenge: &str, difficulty: u8) -> String {
+        (0u64..)
+            .map(|n| n.to_string())
+            .find(|solution| solution_zero_bits(challenge, solution) >= difficulty as u32)
+            .unwrap()
+    }
+
+    #[test]
+    fn zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x1f]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn solved_challenge() {
+        let challenge = new_challenge(SECRET, "poll", NOW);
+        let solution = solve(&challenge, 8);
+        assert_eq!(
+            verify(SECRET, "poll", &challenge, &solution, 8, NOW),
+            Ok(NOW + CHALLENGE_LIFETIME)
+        );
+    }
+
+    #[test]
+    fn invalid_challenges() {
+        let challenge = new_challenge(SECRET, "poll", NOW);
+        let solution = solve(&challenge, 8);
+
+        // Different scope, secret, or a forged expiry time
+        assert!(verify(SECRET, "other", &challenge, &solution, 8, NOW).is_err());
+        assert!(verify("other", "poll", &challenge, &solution, 8, NOW).is_err());
+        let forged = challenge.replacen(&(NOW + CHALLENGE_LIFETIME).to_string(), "9999999999", 1);
+        assert!(verify(SECRET, "poll", &forged, &solution, 8, NOW).is_err());
+        assert!(verify(SECRET, "poll", "garbage", &solution, 8, NOW).is_err());
+
+        // Expired
+        assert!(verify(
+            SECRET,
+            "poll",
+            &challenge,
+            &solution,
+            8,
+            NOW + CHALLENGE_LIFETIME
+        )
+        .is_err());
+
+        // Not hard enough
+        let wrong = (0u64..)
+            .map(|n| n.to_string())
+            .find(|solution| solution_zero_bits(&challenge, solution) < 8)
+            .unwrap();
+        assert!(verify(SECRET, "poll", &challenge, &wrong, 8, NOW).is_err());
+    }
+
+    #[test]
+    fn challenges_are_spent() {
+        let spent = SpentChallenges::default();
+        assert!(spent.spend("a", NOW + 10, NOW));
+        assert!(!spent.spend("a", NOW + 10, NOW));
+        assert!(spent.spend("b", NOW + 10, NOW));
+
+        // Once it's expired, it's forgotten
+        assert!(spent.spend("a", NOW + 20, NOW + 10));
+    }
+}
@@ -0,0 +1,131 @@
+//! Link preview pages for polls.
+//!
+//! The frontend is a single-page app, so chat apps and social networks that don't run JavaScript
+//! can't see anything about a poll from its link. `/p/<pollid>` serves a small page with
+//! OpenGraph and Twitter Card metadata that redirects people to the poll on the frontend.
+
+use rocket::http::Status;
+use rocket::response::content::Html;
+use rocket::State;
+
+use crate::config::Config;
+use crate::embed::oembed_url;
+use crate::error::ErrorKind;
+use crate::html::{escape, truncate};
+use crate::metrics::TimedConnection;
+use crate::poll::{winner_names, Poll};
+
+/// The longest description shown in a preview, in characters
+const MAX_DESCRIPTION_LENGTH: usize = 200;
+
+/// Returns the routes that should be made available
+pub fn routes() -> Vec<rocket::Route> {
+    routes![preview]
+}
+
+/// Describes whether a poll is open, or who won it
+fn poll_status(poll: Poll) -> String {
+    let votes = match poll.votes.len() {
+        1 => "1 vote".to_string(),
+        n => format!("{} votes", n),
+    };
+    match poll.winners {
+        Some(_) if poll.votes.is_empty() => "Voting has ended; nobody voted.".to_string(),
+        Some(winners) => {
+            let winners = winner_names(winners, poll.num_winners);
+            let label = if winners.len() == 1 {
+                "Winner"
+            } else {
+                "Winners"
+            };
+            format!(
+                "Voting has ended with {}. {}: {}.",
+                votes,
+                label,
+                winners.join(", ")
+            )
+        }
+        None => format!("Voting is open, with {} so far.", votes),
+    }
+}
+
+/// Builds a page with metadata for link previews, which sends browsers on to `url`.
+/// `oembed` is where the poll's oEmbed response can be found, if there is one.
+fn page(title: &str, description: &str, url: &str, oembed: Option<&str>) -> String {
+    let title = escape(title);
+    let description = escape(description);
+    let url = escape(url);
+    let oembed = match oembed {
+        Some(oembed) => format!(
+            "<link rel=\"alternate\" type=\"application/json+oembed\" href=\"{}\" title=\"{}\">\n",
+            escape(oembed),
+            title
+        ),
+        None => String::new(),
+    };
+    format!(
+        "<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+<meta charset=\"utf-8\">
+<title>{title} | BetterPoll</title>
+<meta name=\"description\" content=\"{description}\">
+<meta property=\"og:type\" content=\"website\">
+<meta property=\"og:site_name\" content=\"BetterPoll\">
+<meta property=\"og:title\" content=\"{title}\">
+<meta property=\"og:description\" content=\"{description}\">
+<meta property=\"og:url\" content=\"{url}\">
+<meta name=\"twitter:card\" content=\"summary\">
+<meta name=\"twitter:title\" content=\"{title}\">
+<meta name=\"twitter:description\" content=\"{description}\">
+<link rel=\"canonical\" href=\"{url}\">
+{oembed}<meta http-equiv=\"refresh\" content=\"0; url={url}\">
+</head>
+<body>
+<p><a href=\"{url}\">{title} on BetterPoll</a></p>
+</body>
+</html>
+",
+        title = title,
+        description = description,
+        url = url,
+        oembed = oembed,
+    )
+}
+
+#[get("/p/<pollid>")]
+async fn preview(
+    mut conn: TimedConnection,
+    config: &State<Config>,
+    pollid: String,
+) -> Result<(Status, Html<String>), ErrorKind> {
+    let url = config.poll_url(&pollid);
+    let poll_id = pollid.clone();
+    let poll = match conn.get_poll_by_id(pollid).await? {
+        Some(poll) => poll,
+        None => {
+            return Ok((
+                Status::NotFound,
+                Html(page(
+                    "Poll not found",
+                    "This poll doesn't exist.",
+                    &url,
+                    None,
+                )),
+            ))
+        }
+    };
+
+    let title = poll.title.clone();
+    let description = if poll.description.trim().is_empty() {
+        poll_status(poll)
+    } else {
+        let description = truncate(&poll.description, MAX_DESCRIPTION_LENGTH);
+        format!("{} {}", poll_status(poll), description)
+    };
+    let oembed = oembed_url(config, &poll_id);
+    Ok((
+        Status::Ok,
+        Html(page(&title, &description, &url, Some(&oembed))),
+    ))
+}
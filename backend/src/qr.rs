@@ -0,0 +1,109 @@
+//! QR codes linking to polls, for showing on a projector or printing out.
+//!
+//! Codes are generated here rather than by a third-party service, so that nobody else learns
+//! which polls are being shared.
+
+use image::{DynamicImage, ImageOutputFormat, Luma};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use rocket::http::ContentType;
+use rocket::State;
+
+use crate::config::Config;
+use crate::error::{ErrorKind, VisibleError};
+use crate::metrics::TimedConnection;
+
+/// The width and height of a code if none is requested, in pixels
+const DEFAULT_SIZE: u32 = 256;
+/// The smallest and largest sizes that can be requested, in pixels
+const MIN_SIZE: u32 = 64;
+const MAX_SIZE: u32 = 2048;
+
+/// Returns the routes that should be made available
+pub fn routes() -> Vec<rocket::Route> {
+    routes![qr_svg, qr_png]
+}
+
+/// Checks the requested size, and makes a code for the poll's page on the frontend
+async fn poll_code(
+    conn: &mut TimedConnection,
+    config: &Config,
+    pollid: String,
+    size: Option<u32>,
+) -> Result<(QrCode, u32), ErrorKind> {
+    let size = size.unwrap_or(DEFAULT_SIZE);
+    if !(MIN_SIZE..=MAX_SIZE).contains(&size) {
+        return Err(VisibleError::InvalidQuery(format!(
+            "The size must be between {} and {} pixels.",
+            MIN_SIZE, MAX_SIZE
+        ))
+        .into());
+    }
+    if conn.get_poll_by_id(pollid.clone()).await?.is_none() {
+        return Err(VisibleError::PollNotFound(pollid).into());
+    }
+    Ok((QrCode::new(config.poll_url(&pollid))?, size))
+}
+
+/// Renders a code as SVG, at least `size` pixels across
+fn to_svg(code: &QrCode, size: u32) -> String {
+    code.render::<svg::Color>()
+        .min_dimensions(size, size)
+        .dark_color(svg::Color("#000000"))
+        .light_color(svg::Color("#ffffff"))
+        .build()
+}
+
+/// Renders a code as a PNG image, at least `size` pixels across
+fn to_png(code: &QrCode, size: u32) -> Result<Vec<u8>, ErrorKind> {
+    let image = code.render::<Luma<u8>>().min_dimensions(size, size).build();
+    let mut png = vec![];
+    DynamicImage::ImageLuma8(image).write_to(&mut png, ImageOutputFormat::Png)?;
+    Ok(png)
+}
+
+#[get("/poll/<pollid>/qr.svg?<size>")]
+async fn qr_svg(
+    mut conn: TimedConnection,
+    config: &State<Config>,
+    pollid: String,
+    size: Option<u32>,
+) -> Result<(ContentType, String), ErrorKind> {
+    let (code, size) = poll_code(&mut conn, config, pollid, size).await?;
+    Ok((ContentType::SVG, to_svg(&code, size)))
+}
+
+#[get("/poll/<pollid>/qr.png?<size>")]
+async fn qr_png(
+    mut conn: TimedConnection,
+    config: &State<Config>,
+    pollid: String,
+    size: Option<u32>,
+) -> Result<(ContentType, Vec<u8>), ErrorKind> {
+    let (code, size) = poll_code(&mut conn, config, pollid, size).await?;
+    Ok((ContentType::PNG, to_png(&code, size)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rendering() {
+        let code = QrCode::new("https://betterpoll.cc/poll/lunch").unwrap();
+
+        let svg = to_svg(&code, 300);
+        assert!(svg.contains("<svg"));
+        assert!(svg.contains("#000000"));
+
+        let png = to_png(&code, 300).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        let image = image::load_from_memory(&png).unwrap().to_luma8();
+        assert!(image.width() >= 300);
+        assert_eq!(image.width(), image.height());
+        // The quiet zone around the code is white, and the finder pattern in the corner is black
+        let module = image.width() / (code.width() as u32 + 8);
+        assert_eq!(image.get_pixel(0, 0)[0], 255);
+        assert_eq!(image.get_pixel(module * 4, module * 4)[0], 0);
+    }
+}
@@ -0,0 +1,229 @@
+//! Per-client rate limiting for expensive or abusable endpoints
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::Deserialize;
+
+use crate::config::Config;
+use crate::ip::{network_prefix, ClientIp};
+
+/// Limits on how often a client can use a route.
+///
+/// A client starts with `burst` requests available, and gets back one every `60 / per_minute` seconds.
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_minute: f64,
+}
+
+/// The default limits, keyed by route name
+pub fn default_rate_limits() -> HashMap<String, RateLimit> {
+    let mut limits = HashMap::new();
+    limits.insert(
+        "create".to_string(),
+        RateLimit {
+            burst: 10,
+            per_minute: 2.0,
+        },
+    );
+    limits.insert(
+        "create_blt".to_string(),
+        RateLimit {
+            burst: 10,
+            per_minute: 2.0,
+        },
+    );
+    limits.insert(
+        "vote".to_string(),
+        RateLimit {
+            burst: 20,
+            per_minute: 20.0,
+        },
+    );
+    limits
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl Bucket {
+    /// Refills the bucket for the time since it was last used
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_update)
+            .as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.limit.per_minute / 60.0).min(self.limit.burst as f64);
+        self.last_update = now;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.limit.burst as f64
+    }
+}
+
+/// Token buckets for every client and route.
+///
+/// Clones share the same buckets, so that the background jobs can prune them.
+#[derive(Debug, Default, Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<(String, IpAddr), Bucket>>>,
+}
+
+impl RateLimiter {
+    /// Takes a token from the client's bucket for the route, returning `false` if there are none left
+    pub fn check(&self, route: &str, client: IpAddr, limit: &RateLimit, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().expect("rate limiter lock was poisoned");
+        let bucket = buckets
+            .entry((route.to_string(), client))
+            .or_insert(Bucket {
+                limit: *limit,
+                tokens: limit.burst as f64,
+                last_update: now,
+            });
+        bucket.refill(now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forgets buckets that have refilled, since a full bucket behaves the same as one that
+    /// doesn't exist yet
+    pub fn prune(&self, now: Instant) {
+        let mut buckets = self.buckets.lock().expect("rate limiter lock was poisoned");
+        buckets.retain(|_, bucket| {
+            bucket.refill(now);
+            !bucket.is_full()
+        });
+    }
+}
+
+/// Request guard that fails with 429 Too Many Requests if the client has used the route too often.
+///
+/// Routes without a limit in `rate_limits` aren't limited.
+pub struct RateLimited;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimited {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let route = match request.route().and_then(|route| route.name.as_deref()) {
+            Some(route) => route,
+            None => return Outcome::Success(RateLimited),
+        };
+        let (config, limiter) = match (
+            request.rocket().state::<Config>(),
+            request.rocket().state::<RateLimiter>(),
+        ) {
+            (Some(config), Some(limiter)) => (config, limiter),
+            _ => return Outcome::Success(RateLimited),
+        };
+        let limit = match config.rate_limits.get(route) {
+            Some(limit) => limit,
+            None => return Outcome::Success(RateLimited),
+        };
+        let client = match request.guard::<ClientIp>().await {
+            Outcome::Success(ClientIp(ip)) => ip,
+            _ => return Outcome::Success(RateLimited),
+        };
+        let client = network_prefix(client, config.ipv4_prefix_length, config.ipv6_prefix_length);
+
+        if limiter.check(route, client, limit, Instant::now()) {
+            Outcome::Success(RateLimited)
+        } else {
+            Outcome::Failure((Status::TooManyRequests, ()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        burst: 2,
+        per_minute: 60.0,
+    };
+
+    #[test]
+    fn token_bucket() {
+        let limiter = RateLimiter::default();
+        let ip = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.check("vote", ip, &LIMIT, start));
+        assert!(limiter.check("vote", ip, &LIMIT, start));
+        assert!(!limiter.check("vote", ip, &LIMIT, start));
+
+        // One token per second comes back
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check("vote", ip, &LIMIT, later));
+        assert!(!limiter.check("vote", ip, &LIMIT, later));
+
+        // But never more than the burst size
+        let much_later = start + Duration::from_secs(100);
+        assert!(limiter.check("vote", ip, &LIMIT, much_later));
+        assert!(limiter.check("vote", ip, &LIMIT, much_later));
+        assert!(!limiter.check("vote", ip, &LIMIT, much_later));
+    }
+
+    #[test]
+    fn separate_buckets() {
+        let limiter = RateLimiter::default();
+        let ip1 = "192.0.2.1".parse().unwrap();
+        let ip2 = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.check("vote", ip1, &LIMIT, now));
+        assert!(limiter.check("vote", ip1, &LIMIT, now));
+        assert!(!limiter.check("vote", ip1, &LIMIT, now));
+
+        assert!(limiter.check("vote", ip2, &LIMIT, now));
+        assert!(limiter.check("create", ip1, &LIMIT, now));
+    }
+
+    #[test]
+    fn pruning() {
+        let limiter = RateLimiter::default();
+        let ip1 = "192.0.2.1".parse().unwrap();
+        let ip2 = "192.0.2.2".parse().unwrap();
+        let start = Instant::now();
+        let second = start + Duration::from_secs(1);
+
+        assert!(limiter.check("vote", ip1, &LIMIT, start));
+        assert!(limiter.check("vote", ip1, &LIMIT, start));
+        assert!(limiter.check("vote", ip2, &LIMIT, second));
+        assert!(limiter.check("vote", ip2, &LIMIT, second));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+
+        // Two seconds in, the first client's bucket has refilled, but the second's has only got
+        // one token back
+        let later = start + Duration::from_secs(2);
+        limiter.clone().prune(later);
+        let buckets: Vec<_> = limiter.buckets.lock().unwrap().keys().cloned().collect();
+        assert_eq!(buckets, vec![("vote".to_string(), ip2)]);
+
+        // Pruning doesn't change how many requests a client can make
+        assert!(limiter.check("vote", ip1, &LIMIT, later));
+        assert!(limiter.check("vote", ip1, &LIMIT, later));
+        assert!(!limiter.check("vote", ip1, &LIMIT, later));
+        assert!(limiter.check("vote", ip2, &LIMIT, later));
+        assert!(!limiter.check("vote", ip2, &LIMIT, later));
+    }
+}
@@ -0,0 +1,392 @@
+//! Webhooks, which are sent signed JSON when something happens to a poll.
+//!
+//! Events are queued in the `webhook_deliveries` table, which doubles as the delivery log, and
+//! sent by a background job. Failed requests are retried with exponential backoff.
+//!
+//! Anyone can add a webhook, so webhooks are only sent to public addresses; otherwise, they could
+//! be used to probe the server's internal network. The host is checked both when a webhook is
+//! added and each time it's sent, and redirects aren't followed.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use rocket::serde::json::{json, Value};
+use rocket::tokio::net::lookup_host;
+
+use crate::crypto;
+use crate::database::postgres::{PostgresConnection, WebhookDelivery};
+use crate::error::ErrorKind;
+use crate::logging::{self, Level};
+use crate::poll::winner_names;
+
+/// A ballot was cast; the payload includes the number of ballots, but not the ballot itself
+pub const VOTE_CAST: &str = "vote_cast";
+/// The poll ended; the payload includes the winners
+pub const POLL_CLOSED: &str = "poll_closed";
+/// The poll was deleted
+pub const POLL_DELETED: &str = "poll_deleted";
+
+/// How many times a request is attempted before giving up
+pub const MAX_ATTEMPTS: i32 = 8;
+/// How long to wait before the first retry; each retry after that waits twice as long
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// How long to wait for a webhook to respond
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// The most requests sent each time the delivery job runs
+const BATCH_SIZE: i64 = 50;
+
+/// The most webhooks that a poll can have
+pub const MAX_WEBHOOKS_PER_POLL: usize = 5;
+/// The longest URL that a webhook can have
+pub const MAX_URL_LENGTH: usize = 2048;
+
+/// Builds the JSON sent for an event. The properties of `data`, which should be a JSON object,
+/// are added to it.
+pub fn payload(event: &str, poll_id: &str, data: Value) -> Value {
+    let mut payload = match data {
+        Value::Object(data) => data,
+        _ => Default::default(),
+    };
+    payload.insert("event".to_string(), json!(event));
+    payload.insert("pollId".to_string(), json!(poll_id));
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    payload.insert("timestamp".to_string(), json!(timestamp));
+    Value::Object(payload)
+}
+
+/// How long to wait before retrying a request that has failed `attempts` times,
+/// or `None` if it shouldn't be retried
+pub fn retry_delay(attempts: i32) -> Option<Duration> {
+    if attempts >= MAX_ATTEMPTS {
+        return None;
+    }
+    Some(FIRST_RETRY_DELAY * 2u32.pow(attempts.max(1) as u32 - 1))
+}
+
+/// Checks that a webhook URL can be used, returning why it can't if not
+pub fn validate_url(url: &str) -> Result<(), String> {
+    if url.len() > MAX_URL_LENGTH {
+        return Err(format!(
+            "Webhook URLs can't be longer than {} characters.",
+            MAX_URL_LENGTH
+        ));
+    }
+    match Url::parse(url) {
+        Ok(url) if (url.scheme() == "http" || url.scheme() == "https") && url.has_host() => Ok(()),
+        _ => Err("The webhook URL must be an absolute http or https URL.".to_string()),
+    }
+}
+
+/// Whether webhooks can be sent to an address. Loopback, private, link-local, unique-local and
+/// multicast addresses (among others that aren't reachable on the internet) can't be used, and
+/// IPv6 addresses that embed an IPv4 address are judged by that address.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                // Reserved (240.0.0.0/4), which includes the broadcast address
+                || first >= 240
+                // Shared address space for carrier-grade NAT (100.64.0.0/10)
+                || (first == 100 && (second & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            let embedded = |high: u16, low: u16| {
+                IpAddr::V4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)))
+            };
+            if ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() {
+                false
+            } else if segments[..5] == [0; 5] && (segments[5] == 0 || segments[5] == 0xffff) {
+                // IPv4-compatible and IPv4-mapped addresses
+                is_public(embedded(segments[6], segments[7]))
+            } else if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                // NAT64 (64:ff9b::/96) reaches the IPv4 address in the last 32 bits
+                is_public(embedded(segments[6], segments[7]))
+            } else if segments[0] == 0x2002 {
+                // 6to4 (2002::/16) reaches the IPv4 address in the next 32 bits
+                is_public(embedded(segments[1], segments[2]))
+            } else {
+                // Teredo (2001::/32) hides the IPv4 address it reaches, so it isn't used at all
+                segments[..2] != [0x2001, 0]
+                    // Unique-local (fc00::/7) and link-local (fe80::/10)
+                    && (segments[0] & 0xfe00) != 0xfc00
+                    && (segments[0] & 0xffc0) != 0xfe80
+            }
+        }
+    }
+}
+
+/// Looks up the addresses of a webhook URL's host, failing if any of them isn't public (unless
+/// `allow_private` is set). Returns the host's domain name, if it has one, and the addresses.
+pub async fn resolve(
+    url: &str,
+    allow_private: bool,
+) -> Result<(Option<String>, Vec<SocketAddr>), String> {
+    let url = Url::parse(url).map_err(|_| "The webhook URL isn't valid.".to_string())?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let host = match url.host_str() {
+        Some(host) => host,
+        None => return Err("The webhook URL has no host.".to_string()),
+    };
+    // IPv6 addresses are in square brackets
+    let (domain, addresses) = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => (None, vec![SocketAddr::new(ip, port)]),
+        Err(_) => {
+            let addresses = lookup_host((host, port))
+                .await
+                .map_err(|_| format!("The webhook's host '{}' couldn't be found.", host))?;
+            (Some(host.to_string()), addresses.collect())
+        }
+    };
+    if addresses.is_empty() {
+        return Err("The webhook's host has no addresses.".to_string());
+    }
+    if !allow_private && !addresses.iter().all(|address| is_public(address.ip())) {
+        return Err("Webhooks can only be sent to public addresses.".to_string());
+    }
+    Ok((domain, addresses))
+}
+
+/// Builds the HTTP client used to send a webhook, which only connects to `address`.
+///
+/// Pinning the address means the host can't resolve to a different (private) address between
+/// the check in [`resolve`] and the request.
+fn http_client(domain: Option<&str>, address: SocketAddr) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .redirect(Policy::none())
+        .user_agent(concat!("BetterPoll/", env!("CARGO_PKG_VERSION")));
+    if let Some(domain) = domain {
+        builder = builder.resolve(domain, address);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// Sends one request to a webhook, returning the response's status code (if there was a response)
+/// and an error message if it failed
+async fn send(delivery: &WebhookDelivery, allow_private: bool) -> (Option<i32>, Option<String>) {
+    let http = match resolve(&delivery.url, allow_private).await {
+        Ok((domain, addresses)) => match http_client(domain.as_deref(), addresses[0]) {
+            Ok(http) => http,
+            Err(e) => return (None, Some(e)),
+        },
+        Err(e) => return (None, Some(e)),
+    };
+    let signature = crypto::sign(&delivery.secret, delivery.payload.as_bytes());
+    let result = http
+        .post(&delivery.url)
+        .header("Content-Type", "application/json")
+        .header("X-BetterPoll-Event", &delivery.event)
+        .header("X-BetterPoll-Delivery", delivery.id.to_string())
+        .header("X-BetterPoll-Signature", format!("sha256={}", signature))
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+    match result {
+        Ok(response) if response.status().is_success() => {
+            (Some(response.status().as_u16() as i32), None)
+        }
+        Ok(response) => (
+            Some(response.status().as_u16() as i32),
+            Some(format!("The webhook responded with {}.", response.status())),
+        ),
+        Err(e) => (None, Some(e.to_string())),
+    }
+}
+
+/// Sends the requests that are due, returning how many were delivered successfully.
+/// If `allow_private` is set, requests can be sent to addresses that aren't public.
+pub async fn deliver_due(
+    conn: &mut PostgresConnection,
+    allow_private: bool,
+) -> Result<usize, ErrorKind> {
+    let mut delivered = 0;
+    for delivery in conn.get_due_webhook_deliveries(BATCH_SIZE).await? {
+        let (status, error) = send(&delivery, allow_private).await;
+        let next_attempt_at = match error {
+            Some(_) => retry_delay(delivery.attempts + 1).map(|delay| SystemTime::now() + delay),
+            None => {
+                delivered += 1;
+                None
+            }
+        };
+        if let (Some(error), None) = (&error, next_attempt_at) {
+            logging::log(
+                Level::Warn,
+                "Giving up on a webhook delivery.",
+                json!({ "delivery_id": delivery.id, "url": delivery.url, "error": error }),
+            );
+        }
+        conn.record_webhook_attempt(delivery.id, error.is_none(), status, error, next_attempt_at)
+            .await?;
+    }
+    Ok(delivered)
+}
+
+/// Queues the `poll_closed` event for polls that have ended since this last ran
+pub async fn queue_closed_polls(conn: &mut PostgresConnection) -> Result<(), ErrorKind> {
+    for poll_id in conn.get_closed_polls_to_notify().await? {
+        let poll = match conn.get_poll_by_id(poll_id.clone()).await? {
+            Some(poll) => poll,
+            None => continue,
+        };
+        let winners = match poll.winners {
+            Some(winners) => winner_names(winners, poll.num_winners),
+            None => continue,
+        };
+        let payload = payload(
+            POLL_CLOSED,
+            &poll_id,
+            json!({ "numVotes": poll.votes.len(), "winners": winners }),
+        );
+        conn.queue_poll_closed_event(poll_id, payload.to_string())
+            .await?;
+    }
+    Ok(())
+}
+
+/// Describes a delivery in the API's delivery log
+pub fn delivery_json(delivery: &WebhookDelivery) -> Value {
+    let status = if delivery.delivered_at.is_some() {
+        "delivered"
+    } else if delivery.next_attempt_at.is_some() {
+        "pending"
+    } else {
+        "failed"
+    };
+    json!({
+        "id": delivery.id,
+        "webhookId": delivery.webhook_id,
+        "event": delivery.event,
+        "status": status,
+        "attempts": delivery.attempts,
+        "createdAt": delivery.created_at,
+        "nextAttemptAt": delivery.next_attempt_at,
+        "deliveredAt": delivery.delivered_at,
+        "lastStatusCode": delivery.last_status,
+        "lastError": delivery.last_error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        assert_eq!(retry_delay(1), Some(Duration::from_secs(30)));
+        assert_eq!(retry_delay(2), Some(Duration::from_secs(60)));
+        assert_eq!(retry_delay(3), Some(Duration::from_secs(120)));
+        assert_eq!(
+            retry_delay(MAX_ATTEMPTS - 1),
+            Some(Duration::from_secs(30 * 64))
+        );
+        assert_eq!(retry_delay(MAX_ATTEMPTS), None);
+    }
+
+    #[test]
+    fn urls() {
+        assert!(validate_url("https://example.com/hook").is_ok());
+        assert!(validate_url("http://127.0.0.1:8080/").is_ok());
+        assert!(validate_url("http://[::1]/").is_ok());
+        assert!(validate_url("ftp://example.com/").is_err());
+        assert!(validate_url("not a url").is_err());
+        assert!(validate_url(&format!(
+            "https://example.com/{}",
+            "a".repeat(MAX_URL_LENGTH)
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn public_addresses() {
+        for public in [
+            "93.184.216.34",
+            "100.128.0.1",
+            "2606:2800:220:1::1",
+            "::ffff:8.8.8.8",
+            "64:ff9b::808:808",
+            "2002:808:808::1",
+        ] {
+            assert!(is_public(public.parse().unwrap()), "{} is public", public);
+        }
+        for private in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "224.0.0.1",
+            "239.255.255.250",
+            "240.0.0.1",
+            "ff02::1",
+            "ff0e::1",
+            "64:ff9b::a01:203",
+            "64:ff9b::7f00:1",
+            "2002:a01:203::1",
+            "2002:7f00:1::1",
+            "2001:0:4136:e378:8000:63bf:3fff:fdd2",
+        ] {
+            assert!(
+                !is_public(private.parse().unwrap()),
+                "{} is private",
+                private
+            );
+        }
+    }
+
+    #[test]
+    fn resolving() {
+        let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+        for private in [
+            "http://127.0.0.1:8080/",
+            "http://[::1]/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://localhost/",
+        ] {
+            assert!(
+                runtime.block_on(resolve(private, false)).is_err(),
+                "{}",
+                private
+            );
+        }
+        let (domain, addresses) = runtime
+            .block_on(resolve("http://127.0.0.1:8080/", true))
+            .unwrap();
+        assert_eq!(domain, None);
+        assert_eq!(addresses, vec!["127.0.0.1:8080".parse().unwrap()]);
+    }
+
+    #[test]
+    fn payloads() {
+        let payload = payload(
+            VOTE_CAST,
+            "abc",
+            json!({ "numVotes": 3, "event": "ignored" }),
+        );
+        assert_eq!(payload["event"], VOTE_CAST);
+        assert_eq!(payload["pollId"], "abc");
+        assert_eq!(payload["numVotes"], 3);
+        assert!(payload["timestamp"].as_u64().unwrap() > 0);
+    }
+}
@@ -0,0 +1,36 @@
+import {NuxtAxiosInstance} from '@nuxtjs/axios'
+
+/** Counts the zero bits at the start of a hash */
+function leadingZeroBits(hash: Uint8Array) {
+  let bits = 0
+  for (const byte of hash) {
+    if (byte === 0) {
+      bits += 8
+      continue
+    }
+    bits += Math.clz32(byte) - 24
+    break
+  }
+  return bits
+}
+
+/**
+ * Gets a proof-of-work challenge from the backend and solves it.
+ *
+ * Returns the `challenge` and `solution` properties to send along with the request,
+ * or nothing if the backend doesn't require proof of work.
+ */
+export async function solveChallenge(axios: NuxtAxiosInstance, url: string): Promise<{[k: string]: string}> {
+  const data = await axios.$get(url)
+  if (!data.success || !data.difficulty) return {}
+
+  const challenge: string = data.challenge
+  const encoder = new TextEncoder()
+  for (let n = 0; ; n++) {
+    const solution = n.toString()
+    const hash = await crypto.subtle.digest('SHA-256', encoder.encode(`${challenge}:${solution}`))
+    if (leadingZeroBits(new Uint8Array(hash)) >= data.difficulty) {
+      return {challenge, solution}
+    }
+  }
+}